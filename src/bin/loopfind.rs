@@ -0,0 +1,50 @@
+use music_syn::{
+    sample_reader::*,
+    loopfind::find_loop,
+    types::*,
+    writer::*,
+};
+use std::env;
+
+// Finds a sustain loop for each sample. WAV files get it as their smpl
+// chunk, others in a loop manifest next to them.
+fn main() -> R<()> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let dry_run = args.first().is_some_and(|a| a == "-n");
+    if dry_run {
+        args.remove(0);
+    }
+    if args.is_empty() {
+        println!("Usage: loopfind [-n] $SAMPLE...");
+        println!("  -n: only print the loops found");
+        return Ok(());
+    }
+
+    for path in &args {
+        let pcm = load_pcm(path)?;
+        // Analyse the channels mixed down.
+        let len = pcm.channels.iter().map(|c| c.len()).min().unwrap_or(0);
+        let mono: Vec<f32> = (0..len)
+            .map(|i| pcm.channels.iter().map(|c| c[i]).sum::<f32>())
+            .collect();
+
+        let l = match find_loop(&mono, pcm.sample_rate) {
+            Some(l) => l,
+            None => {
+                println!("{}: no loop found", path);
+                continue;
+            }
+        };
+        println!("{}: {} - {} ({:.3} s)", path, l.start, l.end,
+                 (l.end - l.start) as f64 / pcm.sample_rate);
+        if dry_run {
+            continue;
+        }
+        if path.to_lowercase().ends_with(".wav") {
+            write_smpl(path, &l)?;
+        } else {
+            save_loop_manifest(path, &l)?;
+        }
+    }
+    Ok(())
+}
@@ -3,50 +3,115 @@ use music_syn::{
     playback::*,
     types::*,
     midisyn::*,
+    sequencer::sequence,
     instr::*,
     writer::*,
-    geniter::GenIter,
+    transport::*,
 };
 use std::env;
+use std::io::{self, BufRead};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
-fn gen_play(m0: &mut MidiSyn,
-            m1: &mut MidiSyn,
-            es: &[rimd::TrackEvent],
-            out_path: Option<&str>) -> R<()> {
-    let ss0 = GenIter(m0.syn_gen(es))
-        .into_iter()
-        .flat_map(|x| x.into_iter());
-    let ss1 = GenIter(m1.syn_gen(es))
-        .into_iter()
-        .flat_map(|x| x.into_iter());
+const HELP: &str = "Commands: p (pause/resume), s SECS (seek), b BAR (go to bar), \
+                    l FIRST LAST (loop bars), l (stop looping), q (quit)";
 
-    let ss = ss0.zip(ss1)
-        .flat_map(|(x, y)| vec![x, y])
-        .map(|x| x * 0.5);
+// Returns false to quit.
+fn control(t: &TransportHandle, line: &str, paused: &mut bool) -> bool {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let nums: Vec<f64> = words.iter().skip(1).filter_map(|w| w.parse().ok()).collect();
+    match (words.first().cloned(), nums.as_slice()) {
+        (Some("q"), _) => return false,
+        (Some("p"), _) => {
+            *paused = !*paused;
+            if *paused {
+                t.pause();
+            } else {
+                t.resume();
+            }
+        }
+        (Some("s"), &[secs]) => t.seek_time(secs),
+        (Some("b"), &[bar]) => t.seek_bar(bar as u32),
+        (Some("l"), &[first, last]) => t.loop_bars(first as u32, last as u32),
+        (Some("l"), &[]) => t.stop_looping(),
+        _ => println!("{}", HELP),
+    }
+    true
+}
+
+// Removes name and the n values after it from args, returning the values.
+fn take_option(args: &mut Vec<String>, name: &str, n: usize) -> Option<Vec<String>> {
+    let ix = args.iter().position(|a| a == name)?;
+    let end = (ix + 1 + n).min(args.len());
+    let values: Vec<String> = args.drain(ix..end).skip(1).collect();
+    if values.len() == n {
+        Some(values)
+    } else {
+        None
+    }
+}
+
+fn gen_play(m: MidiSyn, out_path: Option<&str>) -> R<()> {
+    // Render in small blocks so that playback never waits on a long
+    // stretch of synthesis.
+    let block_len = 64;
 
     if let Some(out_path) = out_path {
         println!("Writing to {}...", out_path);
-        save_wav(ss, out_path, 2)?;
+        save_wav(m.samples(block_len), out_path, 2)?;
     } else {
-        // let ss: Vec<f32> = ss.collect();
         println!("Playing...");
+        println!("{}", HELP);
         let mut settings = Settings::default();
         settings.channels = 2;
         settings.frames_per_buffer = 640;
-        play(&settings, ss.into_iter())?;
+        let (transport, handle) = Transport::new(m, block_len);
+        let player = Player::start(&settings, transport)?;
+
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut paused = false;
+        while player.is_active()? {
+            if let Ok(line) = lines.recv_timeout(Duration::from_millis(100)) {
+                if !control(&handle, &line, &mut paused) {
+                    break;
+                }
+            }
+        }
+        let stats = player.stop()?;
+        println!("Done playback");
+        if stats.underruns > 0 {
+            println!("{} underruns, {} frames of silence",
+                     stats.underruns, stats.underrun_frames);
+        }
     }
 
     Ok(())
 }
 
 fn main() -> R<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let sf2_file = take_option(&mut args, "--sf2", 1);
+    let drums_dir = take_option(&mut args, "--drums", 1);
+    let mut sfz_files = vec![];
+    while let Some(values) = take_option(&mut args, "--sfz", 2) {
+        sfz_files.push((values[0].parse::<u8>()?, values[1].clone()));
+    }
     let mut out_file: Option<&str> = None;
     if args.len() == 2 {
     } else if args.len() == 3 {
         out_file = Some(&args[2]);
     } else {
-        println!("Usage: {} $MIDI_IN [$WAV_OUT]", args[0]);
+        println!("Usage: {} [--sf2 $SOUNDFONT] [--sfz $PROGRAM $SFZ]... [--drums $DIR] $MIDI_IN [$WAV_OUT]",
+                 args[0]);
         return Ok(());
     }
     let in_file = &args[1];
@@ -54,15 +119,26 @@ fn main() -> R<()> {
     let f = read_midi(in_file)?;
 
     println!("Loading piano samples, this might take several seconds...");
-    let (p0, p1) = Piano::load("samples/normed")?;
+    let piano = Piano::load("samples/normed")?;
 
-    let mut msyn0 = MidiSyn::new(p0);
-    let mut msyn1 = MidiSyn::new(p1);
+    let mut msyn = MidiSyn::new(piano.clone());
+    if let Some(sf2_file) = sf2_file {
+        SoundFont::load(&sf2_file[0])?.register(&mut msyn.instruments);
+        // Our piano samples are still preferred.
+        msyn.instruments.set(PIANO_PROGRAMS, piano);
+    }
+    for (program, sfz_file) in sfz_files {
+        msyn.instruments.set([program], Sfz::load(&sfz_file)?);
+    }
+    if let Some(drums_dir) = drums_dir {
+        let mut kit = synth_kit();
+        kit.load_samples(&drums_dir[0])?;
+        msyn.drum_kits.set(0..=127, kit);
+    }
     // XXX: sanity check >0
-    msyn0.track_state.div = f.division as usize;
-    msyn1.track_state.div = f.division as usize;
-    let events = &f.tracks[0].events;
-    gen_play(&mut msyn0, &mut msyn1, events, out_file)?;
+    msyn.track_state.div = f.division as usize;
+    msyn.load(sequence(&f));
+    gen_play(msyn, out_file)?;
 
     Ok(())
 }
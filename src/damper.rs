@@ -0,0 +1,246 @@
+use std::f64::consts::PI;
+use crate::types::{Frame, SAMPLE_RATE};
+use crate::soundprim::Smoother;
+use crate::instr::Voice;
+
+// CC 64 values between which the dampers are partly lifted: at or below
+// DAMPER_UP they rest on the strings, at or above DAMPER_DOWN they are clear
+// of them.
+const DAMPER_UP: u8 = 16;
+const DAMPER_DOWN: u8 = 112;
+
+// Seconds for a string to fall by 60 dB with the damper resting on it.
+const DAMP_TIME: f64 = 0.1;
+
+// A note ends once it has been damped below this.
+const SILENCE: f32 = 1e-4;
+
+// How far the dampers are lifted by a CC 64 value, from 0 (resting on the
+// strings) to 1 (clear).
+pub fn lift(value: u8) -> f32 {
+    let value = value.clamp(DAMPER_UP, DAMPER_DOWN);
+    (value - DAMPER_UP) as f32 / (DAMPER_DOWN - DAMPER_UP) as f32
+}
+
+// A released note of a string instrument, ringing on as far as the damper
+// lets it. A partly lifted damper takes longer to quiet the string, and
+// leaves some of it ringing. What was damped away stays so, but the rest
+// can be caught by lifting the damper again.
+pub struct Damped {
+    voice: Box<dyn Voice>,
+    // Gain left after damping.
+    level: f32,
+    // Level that the damper stops at.
+    floor: f32,
+    // Per sample damping.
+    coef: f32,
+    buf: Vec<Frame>,
+}
+
+impl Damped {
+    pub fn new(voice: Box<dyn Voice>, lift: f32) -> Self {
+        let mut d = Self {
+            voice,
+            level: 1.0,
+            floor: 0.0,
+            coef: 1.0,
+            buf: vec![],
+        };
+        d.set_lift(lift);
+        d
+    }
+
+    pub fn set_lift(&mut self, lift: f32) {
+        let lift = lift.clamp(0.0, 1.0) as f64;
+        self.floor = (lift * lift) as f32;
+        self.coef = if lift >= 1.0 {
+            1.0
+        } else {
+            let t60 = DAMP_TIME / (1.0 - lift).powi(2);
+            10f64.powf(-3.0 / (t60 * SAMPLE_RATE)) as f32
+        };
+    }
+}
+
+impl Voice for Damped {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        self.buf.clear();
+        self.buf.resize(out.len(), [0.0, 0.0]);
+        let alive = self.voice.render(&mut self.buf);
+        for (o, v) in out.iter_mut().zip(&self.buf) {
+            if self.level > self.floor {
+                self.level = self.floor + (self.level - self.floor) * self.coef;
+            }
+            o[0] += v[0] * self.level;
+            o[1] += v[1] * self.level;
+        }
+        alive && self.level >= SILENCE
+    }
+
+    fn set_pitch_bend(&mut self, ratio: f64) {
+        self.voice.set_pitch_bend(ratio);
+    }
+
+    fn release(&mut self) {
+        self.voice.release();
+    }
+}
+
+// Gain of the strings' ringing relative to what excites them.
+const RESONANCE_LEVEL: f32 = 0.03;
+
+// Seconds for an undamped C4 string to fall by 60 dB. Lower strings ring
+// longer.
+const RESONANCE_DECAY: f64 = 3.0;
+
+// A string tuned to a key, as a two pole resonator.
+struct Resonator {
+    b: f32,
+    a1: f32,
+    a2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Resonator {
+    fn new(key: u8, sample_rate: f64) -> Self {
+        let freq = 440.0 * 2f64.powf((key as f64 - 69.0) / 12.0);
+        let t60 = RESONANCE_DECAY * (261.63 / freq).sqrt();
+        let r = 10f64.powf(-3.0 / (t60 * sample_rate));
+        let theta = 2.0 * PI * freq / sample_rate;
+        // Unity gain at the resonance.
+        let b = (1.0 - r) * (1.0 - 2.0 * r * (2.0 * theta).cos() + r * r).sqrt();
+        Self {
+            b: b as f32,
+            a1: (2.0 * r * theta.cos()) as f32,
+            a2: (r * r) as f32,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b * x + self.a1 * self.y1 - self.a2 * self.y2;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+// Sympathetic resonance: with the dampers lifted, every string of the
+// piano picks up and rings along with what is played near its pitch or
+// its partials.
+pub struct Resonance {
+    strings: Vec<Resonator>,
+    // How far the dampers are lifted.
+    lift: Smoother,
+}
+
+impl Resonance {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            // A0 to C8.
+            strings: (21..=108).map(|key| Resonator::new(key, sample_rate)).collect(),
+            lift: Smoother::new(0.0, DAMP_TIME / 6.9, sample_rate),
+        }
+    }
+
+    // Whether there is anything to process.
+    pub fn is_active(&self, lift: f32) -> bool {
+        lift > 0.0 || self.lift.value() >= SILENCE
+    }
+
+    // Adds the strings' ringing to buf, which they are excited by.
+    pub fn process(&mut self, buf: &mut [Frame], lift: f32) {
+        self.lift.set(lift);
+        for f in buf.iter_mut() {
+            let lift = self.lift.next().unwrap();
+            let x = (f[0] + f[1]) * 0.5 * lift;
+            let y: f32 = self.strings.iter_mut().map(|s| s.process(x)).sum();
+            let y = y * lift * RESONANCE_LEVEL;
+            f[0] += y;
+            f[1] += y;
+        }
+        if !self.is_active(lift) {
+            // Damped for good.
+            self.lift.settle();
+            for s in &mut self.strings {
+                s.y1 = 0.0;
+                s.y2 = 0.0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instr::Tone;
+
+    fn damped(lift: f32) -> Damped {
+        Damped::new(Box::new(Tone::new(1.0, true)), lift)
+    }
+
+    // Level of the last frame after secs.
+    fn play(d: &mut Damped, secs: f64) -> f32 {
+        let mut out = vec![[0.0; 2]; (SAMPLE_RATE * secs) as usize];
+        d.render(&mut out);
+        out.last().unwrap()[0]
+    }
+
+    #[test]
+    fn lift_clamps_to_the_pedal_range() {
+        assert_eq!(lift(0), 0.0);
+        assert_eq!(lift(DAMPER_UP), 0.0);
+        assert_eq!(lift(64), 0.5);
+        assert_eq!(lift(DAMPER_DOWN), 1.0);
+        assert_eq!(lift(127), 1.0);
+    }
+
+    #[test]
+    fn lifting_catches_without_raising() {
+        let mut d = damped(0.0);
+        let before = play(&mut d, 0.05);
+        assert!(before > SILENCE && before < 0.5);
+        d.set_lift(1.0);
+        let after = play(&mut d, 0.5);
+        assert!((after - before).abs() < before * 0.01);
+    }
+
+    #[test]
+    fn half_pedal_leaves_a_floor() {
+        let mut d = damped(0.5);
+        let level = play(&mut d, 2.0);
+        assert!((level - 0.25).abs() < 0.01);
+        assert!(d.render(&mut [[0.0; 2]; 64]));
+
+        // All the way down damps the rest.
+        d.set_lift(0.0);
+        assert!(!d.render(&mut vec![[0.0; 2]; SAMPLE_RATE as usize]));
+    }
+
+    #[test]
+    fn resonance_stops_once_damped() {
+        let mut r = Resonance::new(SAMPLE_RATE);
+        let mut buf: Vec<Frame> = (0..SAMPLE_RATE as usize / 10)
+            .map(|i| {
+                let x = (2.0 * PI * 440.0 * i as f64 / SAMPLE_RATE).sin() as f32;
+                [x, x]
+            })
+            .collect();
+        r.process(&mut buf, 1.0);
+        assert!(r.is_active(0.0));
+
+        // Rings on in silence while lifted.
+        let mut buf = vec![[0.0; 2]; 4410];
+        r.process(&mut buf, 1.0);
+        assert!(buf.iter().any(|f| f[0].abs() > 1e-4));
+
+        let mut buf = vec![[0.0; 2]; SAMPLE_RATE as usize];
+        r.process(&mut buf, 0.0);
+        assert!(!r.is_active(0.0));
+        let mut buf = vec![[0.0; 2]; 4410];
+        r.process(&mut buf, 0.0);
+        assert!(buf.iter().all(|f| f[0] == 0.0 && f[1] == 0.0));
+    }
+}
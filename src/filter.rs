@@ -0,0 +1,66 @@
+use std::f64::consts::PI;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FilterMode {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+// Resonant state variable filter, in its trapezoidal integrated form so that
+// the cutoff can change from sample to sample without blowing up.
+pub struct Svf {
+    pub mode: FilterMode,
+    sample_rate: f64,
+    // Damping, 2 (none) down to 0 (self-oscillation).
+    k: f64,
+    a1: f64,
+    a2: f64,
+    a3: f64,
+    ic1eq: f64,
+    ic2eq: f64,
+}
+
+impl Svf {
+    pub fn new(mode: FilterMode, cutoff: f64, resonance: f64, sample_rate: f64) -> Self {
+        let mut f = Self {
+            mode,
+            sample_rate,
+            k: 2.,
+            a1: 0.,
+            a2: 0.,
+            a3: 0.,
+            ic1eq: 0.,
+            ic2eq: 0.,
+        };
+        f.set(cutoff, resonance);
+        f
+    }
+
+    // cutoff in Hz, resonance from 0 (none) to 1 (about to self-oscillate).
+    pub fn set(&mut self, cutoff: f64, resonance: f64) {
+        let cutoff = cutoff.clamp(10., self.sample_rate * 0.49);
+        let g = (PI * cutoff / self.sample_rate).tan();
+        self.k = 2. - 2. * resonance.clamp(0., 0.99);
+        self.a1 = 1. / (1. + g * (g + self.k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let v0 = x as f64;
+        let v3 = v0 - self.ic2eq;
+        let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+        let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+        self.ic1eq = 2. * v1 - self.ic1eq;
+        self.ic2eq = 2. * v2 - self.ic2eq;
+        let y = match self.mode {
+            FilterMode::LowPass => v2,
+            FilterMode::BandPass => v1,
+            FilterMode::HighPass => v0 - self.k * v1 - v2,
+            FilterMode::Notch => v0 - self.k * v1,
+        };
+        y as f32
+    }
+}
@@ -0,0 +1,306 @@
+use std::iter;
+use std::sync::atomic::{AtomicU32, Ordering};
+use crate::types::{Frame, Sound, SAMPLE_RATE};
+use crate::soundprim::{mult, superpos, pan_gains, Adsr, Curve};
+use crate::oscillator::{Oscillator, Waveform, Noise};
+use crate::filter::{Svf, FilterMode};
+use super::voice::{Voice, Releaser};
+use super::drums::{DrumKit, FIRST_DRUM, LAST_DRUM};
+
+// Hits get different noise, so that repeated ones don't sound canned.
+static SEED: AtomicU32 = AtomicU32::new(1);
+
+// Frequencies of the six square waves of an 808's cymbals and hats.
+const METAL: [f64; 6] = [205.3, 304.4, 369.6, 522.7, 540.0, 800.0];
+
+// Falls from 1 to 0 in time seconds, then ends.
+fn decay(time: f64) -> impl Sound {
+    let adsr = Adsr {
+        attack: 0.0005,
+        decay: time,
+        sustain: 0.0,
+        release: 0.0,
+        curve: Curve::Exponential,
+    };
+    adsr.make(SAMPLE_RATE)
+}
+
+fn gain(s: impl Sound, g: f64) -> impl Sound {
+    s.map(move |x| x * g as f32)
+}
+
+// Delays s by time seconds.
+fn after(time: f64, s: impl Sound) -> impl Sound {
+    iter::repeat_n(0.0, (time * SAMPLE_RATE) as usize).chain(s)
+}
+
+fn filtered(s: impl Sound, mode: FilterMode, cutoff: f64, resonance: f64) -> impl Sound {
+    let mut f = Svf::new(mode, cutoff, resonance, SAMPLE_RATE);
+    s.map(move |x| f.process(x))
+}
+
+fn noise(mode: FilterMode, cutoff: f64, resonance: f64) -> impl Sound {
+    let seed = SEED.fetch_add(0x9e37_79b9, Ordering::Relaxed);
+    filtered(Noise::new(seed), mode, cutoff, resonance)
+}
+
+// A struck membrane: a sine starting octaves above freq and falling to it
+// in sweep seconds, dying out in time seconds.
+fn membrane(freq: f64, octaves: f64, sweep: f64, time: f64) -> impl Sound {
+    let bend = decay(sweep).map(move |x| x * octaves as f32).chain(iter::repeat(0.0));
+    mult(Oscillator::new(Waveform::Sine, freq, SAMPLE_RATE).fm(bend), decay(time))
+}
+
+// Inharmonic square waves, for cymbals and bells.
+fn metal(freqs: &[f64], scale: f64) -> impl Sound {
+    let mut oscs: Vec<_> = freqs.iter()
+        .map(|f| Oscillator::new(Waveform::Square, f * scale, SAMPLE_RATE))
+        .collect();
+    let n = oscs.len() as f32;
+    iter::from_fn(move || Some(oscs.iter_mut().filter_map(|o| o.next()).sum::<f32>() / n))
+}
+
+fn kick(v: f64, freq: f64) -> impl Sound {
+    // Harder hits bend further and click more.
+    let body = membrane(freq, 1.5 + v, 0.04, 0.35 + 0.15 * v);
+    let click = gain(mult(noise(FilterMode::HighPass, 2000.0, 0.0), decay(0.004)), 0.3 + 0.5 * v);
+    superpos(body, click)
+}
+
+fn snare(v: f64, snappy: f64) -> impl Sound {
+    let body = superpos(membrane(185.0, 0.3, 0.02, 0.12),
+                        gain(membrane(330.0, 0.3, 0.02, 0.08), 0.5));
+    let wires = noise(FilterMode::HighPass, 1500.0 + 3000.0 * v, 0.1);
+    let wires = gain(mult(wires, decay(0.15 + 0.07 * v)), (0.5 + 0.6 * v) * snappy);
+    superpos(gain(body, 0.8), wires)
+}
+
+fn side_stick(v: f64) -> impl Sound {
+    let wood = membrane(1700.0, 0.2, 0.005, 0.02);
+    let crack = mult(noise(FilterMode::BandPass, 3000.0 + 1000.0 * v, 0.5), decay(0.015));
+    superpos(wood, gain(crack, 2.0))
+}
+
+fn clap(v: f64) -> impl Sound {
+    // A few hands slightly apart, then the room.
+    let bursts = superpos(superpos(decay(0.01), after(0.011, decay(0.01))),
+                          after(0.023, decay(0.12 + 0.05 * v)));
+    gain(mult(noise(FilterMode::BandPass, 1200.0, 0.3), bursts), 2.5)
+}
+
+fn tom(v: f64, freq: f64) -> impl Sound {
+    let body = membrane(freq, 0.6 + 0.4 * v, 0.08, 0.45);
+    let skin = gain(mult(noise(FilterMode::LowPass, 600.0, 0.2), decay(0.05)), 0.3 * v);
+    superpos(body, skin)
+}
+
+fn hi_hat(v: f64, time: f64) -> impl Sound {
+    // Harder hits are brighter.
+    let cutoff = 6000.0 + 3000.0 * v;
+    let shimmer = filtered(metal(&METAL, 1.0), FilterMode::HighPass, cutoff, 0.3);
+    let hiss = gain(noise(FilterMode::HighPass, cutoff + 2000.0, 0.0), 0.5);
+    mult(superpos(shimmer, hiss), decay(time * (0.8 + 0.3 * v)))
+}
+
+fn cymbal(v: f64, center: f64, time: f64, hiss: f64) -> impl Sound {
+    let shimmer = filtered(metal(&METAL, 1.4), FilterMode::BandPass, center, 0.2);
+    let wash = gain(noise(FilterMode::HighPass, center * 0.6 + 2000.0 * v, 0.0), hiss);
+    mult(superpos(shimmer, wash), decay(time * (0.7 + 0.4 * v)))
+}
+
+fn bell(freq: f64, time: f64) -> impl Sound {
+    let partials = superpos(membrane(freq, 0.0, 0.001, time),
+                            gain(membrane(freq * 2.76, 0.0, 0.001, time * 0.6), 0.5));
+    superpos(partials, gain(mult(metal(&[freq, freq * 1.48], 1.0), decay(time * 0.3)), 0.3))
+}
+
+// A membrane with the ring of its metal shell.
+fn timbale(freq: f64) -> impl Sound {
+    let shell = gain(mult(metal(&[freq, freq * 2.4], 1.0), decay(0.1)), 0.2);
+    superpos(membrane(freq, 0.1, 0.01, 0.6), shell)
+}
+
+fn cowbell() -> impl Sound {
+    let tone = filtered(metal(&[540.0, 800.0], 1.0), FilterMode::BandPass, 800.0, 0.5);
+    mult(gain(tone, 2.0), decay(0.3))
+}
+
+fn shaker(cutoff: f64, time: f64) -> impl Sound {
+    mult(noise(FilterMode::HighPass, cutoff, 0.1), decay(time))
+}
+
+fn tambourine(v: f64) -> impl Sound {
+    let jingles = filtered(metal(&METAL, 6.0), FilterMode::HighPass, 7000.0, 0.2);
+    mult(superpos(jingles, noise(FilterMode::BandPass, 9000.0, 0.2)), decay(0.2 + 0.1 * v))
+}
+
+// Noise rattled or scraped at rate Hz.
+fn rattle(center: f64, rate: f64, time: f64) -> impl Sound {
+    let teeth = Oscillator::new(Waveform::Saw, rate, SAMPLE_RATE).map(|x| 0.5 - 0.5 * x);
+    mult(mult(noise(FilterMode::BandPass, center, 0.3), teeth), decay(time))
+}
+
+fn whistle(time: f64) -> impl Sound {
+    let trill = Oscillator::new(Waveform::Sine, 30.0, SAMPLE_RATE).map(|x| x * 0.02);
+    let env = Adsr {
+        attack: 0.01,
+        decay: time,
+        sustain: 0.0,
+        release: 0.0,
+        curve: Curve::Linear,
+    };
+    mult(Oscillator::new(Waveform::Sine, 2300.0, SAMPLE_RATE).fm(trill), env.make(SAMPLE_RATE))
+}
+
+fn wood(freq: f64) -> impl Sound {
+    let knock = mult(noise(FilterMode::BandPass, freq * 2.0, 0.6), decay(0.02));
+    superpos(membrane(freq, 0.1, 0.005, 0.06), knock)
+}
+
+fn cuica(time: f64) -> impl Sound {
+    // Glides up, rather than down.
+    membrane(550.0, -0.7, time, time)
+}
+
+fn triangle(time: f64) -> impl Sound {
+    let partials = superpos(membrane(4000.0, 0.0, 0.001, time),
+                            gain(membrane(4000.0 * 2.71, 0.0, 0.001, time * 0.7), 0.5));
+    superpos(partials, gain(membrane(4000.0 * 5.13, 0.0, 0.001, time * 0.4), 0.3))
+}
+
+// The sound of a GM percussion key at velocity v in [0, 1], and its level
+// and pan.
+fn gm_drum(key: u8, v: f64) -> Option<(Box<dyn Sound>, f64, f32)> {
+    let (s, level, pan): (Box<dyn Sound>, f64, f32) = match key {
+        35 => (Box::new(kick(v, 45.0)), 0.7, 0.5),
+        36 => (Box::new(kick(v, 55.0)), 0.7, 0.5),
+        37 => (Box::new(side_stick(v)), 0.5, 0.45),
+        38 => (Box::new(snare(v, 1.0)), 0.45, 0.45),
+        39 => (Box::new(clap(v)), 0.6, 0.5),
+        40 => (Box::new(snare(v, 1.4)), 0.4, 0.45),
+        41 => (Box::new(tom(v, 82.0)), 0.8, 0.7),
+        42 => (Box::new(hi_hat(v, 0.06)), 0.6, 0.35),
+        43 => (Box::new(tom(v, 98.0)), 0.8, 0.65),
+        44 => (Box::new(hi_hat(v, 0.1)), 0.45, 0.35),
+        45 => (Box::new(tom(v, 110.0)), 0.8, 0.6),
+        46 => (Box::new(hi_hat(v, 0.6)), 0.6, 0.35),
+        47 => (Box::new(tom(v, 131.0)), 0.8, 0.55),
+        48 => (Box::new(tom(v, 147.0)), 0.8, 0.45),
+        49 => (Box::new(cymbal(v, 8000.0, 1.8, 0.6)), 0.5, 0.3),
+        50 => (Box::new(tom(v, 175.0)), 0.8, 0.4),
+        51 => (Box::new(cymbal(v, 9000.0, 2.5, 0.3)), 0.45, 0.7),
+        52 => (Box::new(cymbal(v, 4000.0, 1.5, 0.8)), 0.35, 0.25),
+        53 => (Box::new(bell(700.0, 1.5)), 0.3, 0.7),
+        54 => (Box::new(tambourine(v)), 0.3, 0.6),
+        55 => (Box::new(cymbal(v, 9000.0, 0.8, 0.6)), 0.3, 0.35),
+        56 => (Box::new(cowbell()), 0.4, 0.6),
+        57 => (Box::new(cymbal(v, 7000.0, 1.8, 0.6)), 0.5, 0.7),
+        58 => (Box::new(rattle(3000.0, 30.0, 1.0)), 0.4, 0.3),
+        59 => (Box::new(cymbal(v, 10000.0, 2.5, 0.3)), 0.45, 0.3),
+        60 => (Box::new(membrane(400.0, 0.2, 0.01, 0.15)), 0.6, 0.65),
+        61 => (Box::new(membrane(300.0, 0.2, 0.01, 0.2)), 0.6, 0.65),
+        62 => (Box::new(membrane(350.0, 0.3, 0.01, 0.08)), 0.6, 0.35),
+        63 => (Box::new(membrane(330.0, 0.2, 0.01, 0.3)), 0.6, 0.35),
+        64 => (Box::new(membrane(250.0, 0.2, 0.01, 0.35)), 0.6, 0.35),
+        65 => (Box::new(timbale(500.0)), 0.5, 0.6),
+        66 => (Box::new(timbale(380.0)), 0.5, 0.6),
+        67 => (Box::new(bell(900.0, 0.4)), 0.4, 0.4),
+        68 => (Box::new(bell(660.0, 0.4)), 0.4, 0.4),
+        69 => (Box::new(shaker(6000.0, 0.1)), 0.4, 0.6),
+        70 => (Box::new(shaker(8000.0, 0.05)), 0.4, 0.4),
+        71 => (Box::new(whistle(0.12)), 0.3, 0.5),
+        72 => (Box::new(whistle(0.5)), 0.3, 0.5),
+        73 => (Box::new(rattle(2500.0, 40.0, 0.1)), 0.5, 0.6),
+        74 => (Box::new(rattle(2500.0, 40.0, 0.4)), 0.5, 0.6),
+        75 => (Box::new(membrane(2500.0, 0.0, 0.001, 0.05)), 0.5, 0.4),
+        76 => (Box::new(wood(1200.0)), 0.5, 0.6),
+        77 => (Box::new(wood(900.0)), 0.5, 0.6),
+        78 => (Box::new(cuica(0.15)), 0.4, 0.4),
+        79 => (Box::new(cuica(0.4)), 0.4, 0.4),
+        80 => (Box::new(triangle(0.15)), 0.2, 0.7),
+        81 => (Box::new(triangle(1.5)), 0.2, 0.7),
+        _ => return None,
+    };
+    Some((s, level, pan))
+}
+
+// A GM kit with every drum synthesized, so that nothing has to be loaded.
+pub fn synth_kit() -> DrumKit {
+    let mut kit = DrumKit::new();
+    for key in FIRST_DRUM..=LAST_DRUM {
+        kit.set(key, move |velo| {
+            let v = velo as f64 / 127.0;
+            let (sound, level, pan) = gm_drum(key, v).expect("GM drum");
+            // Softer hits are quieter as well as duller.
+            let (l, r) = pan_gains(pan);
+            let amp = (level * v.powf(1.5)) as f32;
+            Box::new(DrumVoice {
+                sound,
+                gains: (l * amp, r * amp),
+                releaser: Releaser::new(),
+            })
+        });
+    }
+    kit
+}
+
+// A synthesized hit, played out unless choked.
+pub struct DrumVoice {
+    sound: Box<dyn Sound>,
+    gains: (f32, f32),
+    releaser: Releaser,
+}
+
+impl Voice for DrumVoice {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        for o in out.iter_mut() {
+            let v = match (self.sound.next(), self.releaser.next()) {
+                (Some(x), Some(r)) => x * r,
+                _ => return false,
+            };
+            o[0] += v * self.gains.0;
+            o[1] += v * self.gains.1;
+        }
+        true
+    }
+
+    // Drums are unpitched.
+    fn set_pitch_bend(&mut self, _ratio: f64) {}
+
+    // One-shots play on after the key is released.
+    fn release(&mut self) {}
+
+    fn choke(&mut self) {
+        self.releaser.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instr::Instrument;
+
+    // Crash cymbal 1
+    const CRASH: u8 = 49;
+
+    fn peak(out: &[Frame]) -> f32 {
+        out.iter().map(|f| f[0].abs().max(f[1].abs())).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn cymbals_ring_out_until_choked() {
+        let mut kit = synth_kit();
+        let mut v = kit.note_on(9, CRASH, 127).unwrap();
+        let mut out = vec![[0.0; 2]; 4410];
+        v.render(&mut out);
+        v.release();
+        let mut out = vec![[0.0; 2]; 4410];
+        assert!(v.render(&mut out));
+        assert!(peak(&out[4000..]) > 0.01);
+
+        v.choke();
+        let mut out = vec![[0.0; 2]; 8820];
+        assert!(!v.render(&mut out));
+        assert!(peak(&out[4410..]) < 1e-6);
+    }
+}
@@ -0,0 +1,140 @@
+use std::path::Path;
+use std::sync::Arc;
+use crate::types::{R, SAMPLE_RATE};
+use crate::sample_reader::load_pcm;
+use super::sampler::{SampleVoice, Span, LoopMode, Dahdsr};
+use super::voice::{Voice, Layered};
+use super::instrument::Instrument;
+
+// Keys of the GM percussion map.
+pub const FIRST_DRUM: u8 = 35;
+pub const LAST_DRUM: u8 = 81;
+
+// Plays a drum at the given velocity.
+pub type Hit = Box<dyn Fn(u8) -> Box<dyn Voice> + Send>;
+
+// Keys that cut each other off, as a real player can't have the hi-hat
+// open and closed at once.
+fn gm_choke_group(key: u8) -> Option<u8> {
+    match key {
+        // Closed, pedal and open hi-hat.
+        42 | 44 | 46 => Some(1),
+        // Short and long whistle, guiro and cuica.
+        71 | 72 => Some(2),
+        73 | 74 => Some(3),
+        78 | 79 => Some(4),
+        // Mute and open triangle.
+        80 | 81 => Some(5),
+        _ => None,
+    }
+}
+
+// Maps the keys of a percussion channel to drums. Drums are one-shots:
+// they play out regardless of note-off, unless choked.
+pub struct DrumKit {
+    // By key.
+    hits: Vec<Option<Hit>>,
+    pub choke_groups: [Option<u8>; 128],
+}
+
+impl DrumKit {
+    // A kit without drums, with the GM choke groups.
+    pub fn new() -> Self {
+        Self {
+            hits: (0..128).map(|_| None).collect(),
+            choke_groups: std::array::from_fn(|key| gm_choke_group(key as u8)),
+        }
+    }
+
+    pub fn set(&mut self, key: u8, hit: impl Fn(u8) -> Box<dyn Voice> + Send + 'static) {
+        self.hits[key as usize & 0x7f] = Some(Box::new(hit));
+    }
+
+    pub fn has(&self, key: u8) -> bool {
+        self.hits[key as usize & 0x7f].is_some()
+    }
+
+    // Replaces drums with one-shot samples named by their GM key, e.g.
+    // 36.wav for the bass drum. Keys without a sample are left as they are.
+    pub fn load_samples(&mut self, dir: &str) -> R<()> {
+        for key in FIRST_DRUM..=LAST_DRUM {
+            for ext in ["wav", "flac"] {
+                let path = Path::new(dir).join(format!("{}.{}", key, ext));
+                if path.exists() {
+                    let pcm = load_pcm(&path.to_string_lossy())?;
+                    self.set(key, sample_hit(pcm.channels, pcm.sample_rate));
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn sample_hit(channels: Vec<Vec<f32>>, sample_rate: f64) -> impl Fn(u8) -> Box<dyn Voice> {
+    let channels: Vec<Arc<Vec<f32>>> = channels.into_iter().map(Arc::new).collect();
+    let step = sample_rate / SAMPLE_RATE;
+    // Plays to the end, and only fades quickly when choked.
+    let env = Dahdsr {
+        release: 0.05,
+        ..Dahdsr::default()
+    };
+    move |velo| {
+        let gain = (velo as f32 / 127.0).powi(2);
+        // Stereo samples have their channels panned hard.
+        let gains: Vec<(f32, f32)> = match channels.len() {
+            1 => vec![(gain, gain)],
+            _ => vec![(gain, 0.0), (0.0, gain)],
+        };
+        let voices = channels.iter().zip(gains).map(|(data, gains)| {
+            let span = Span {
+                start: 0,
+                end: data.len(),
+                loop_start: 0,
+                loop_end: 0,
+                mode: LoopMode::OneShot,
+                ping_pong: false,
+                crossfade: 0,
+            };
+            SampleVoice::new(data.clone(), span, step, gains, env)
+        }).collect();
+        Box::new(Layered(voices))
+    }
+}
+
+impl Default for DrumKit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Instrument for DrumKit {
+    fn note_on(&mut self, _ch: u8, key: u8, velo: u8) -> Option<Box<dyn Voice>> {
+        let hit = self.hits[key as usize & 0x7f].as_ref()?;
+        Some(hit(velo))
+    }
+
+    fn choke_group(&self, key: u8) -> Option<u8> {
+        self.choke_groups[key as usize & 0x7f]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_ring_out_until_choked() {
+        let hit = sample_hit(vec![vec![0.5; 44100]], SAMPLE_RATE);
+        let mut out = vec![[0.0; 2]; 4410];
+        let mut v = hit(127);
+        v.release();
+        assert!(v.render(&mut out));
+        assert!(out[100..].iter().all(|f| f[0] > 0.4));
+
+        v.choke();
+        let mut out = vec![[0.0; 2]; 4410];
+        assert!(!v.render(&mut out));
+        assert!(out[4000][0] < 1e-3);
+    }
+}
@@ -0,0 +1,291 @@
+use std::f64::consts::PI;
+use std::ops::RangeInclusive;
+use crate::types::Frame;
+use crate::soundprim::{Adsr, AdsrEnv, Curve};
+use super::voice::Voice;
+use super::instrument::Instrument;
+
+pub const NUM_OPS: usize = 4;
+
+// Which operators modulate which, as (from, to), and which are heard.
+// Operators only modulate lower numbered ones, and the top one (3) has the
+// feedback.
+pub struct Algorithm {
+    pub mods: &'static [(usize, usize)],
+    pub carriers: &'static [usize],
+}
+
+// The eight algorithms of the 4-operator Yamaha chips.
+pub const ALGORITHMS: [Algorithm; 8] = [
+    // 3 -> 2 -> 1 -> 0
+    Algorithm { mods: &[(3, 2), (2, 1), (1, 0)], carriers: &[0] },
+    // (3 + 2) -> 1 -> 0
+    Algorithm { mods: &[(3, 1), (2, 1), (1, 0)], carriers: &[0] },
+    // (3 + 2 -> 1) -> 0
+    Algorithm { mods: &[(3, 0), (2, 1), (1, 0)], carriers: &[0] },
+    // (3 -> 2 + 1) -> 0
+    Algorithm { mods: &[(3, 2), (2, 0), (1, 0)], carriers: &[0] },
+    // 3 -> 2, 1 -> 0
+    Algorithm { mods: &[(3, 2), (1, 0)], carriers: &[0, 2] },
+    // 3 -> each of 2, 1, 0
+    Algorithm { mods: &[(3, 2), (3, 1), (3, 0)], carriers: &[0, 1, 2] },
+    // 3 -> 2, 1, 0
+    Algorithm { mods: &[(3, 2)], carriers: &[0, 1, 2] },
+    // 3, 2, 1, 0
+    Algorithm { mods: &[], carriers: &[0, 1, 2, 3] },
+];
+
+#[derive(Copy, Clone, Debug)]
+pub struct Operator {
+    // Frequency over the note's.
+    pub ratio: f64,
+    // Cents
+    pub detune: f64,
+    // Amplitude of a carrier, or peak modulation index (in radians) of a
+    // modulator.
+    pub level: f64,
+    pub env: Adsr,
+    // 0 plays every velocity at full level, 1 scales level with velocity.
+    pub velo_sens: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct FmPatch {
+    pub ops: [Operator; NUM_OPS],
+    // Index into ALGORITHMS.
+    pub algorithm: usize,
+    // Of operator 3 onto itself, in radians.
+    pub feedback: f64,
+    pub gain: f64,
+}
+
+fn op(ratio: f64, level: f64, attack: f64, decay: f64, sustain: f64, release: f64,
+      velo_sens: f64) -> Operator {
+    Operator {
+        ratio,
+        detune: 0.0,
+        level,
+        env: Adsr { attack, decay, sustain, release, curve: Curve::Exponential },
+        velo_sens,
+    }
+}
+
+// Tine and bar, plus a short metallic click that gets louder when hit
+// harder.
+pub fn electric_piano() -> FmPatch {
+    let mut body = op(1.0, 0.6, 0.002, 2.5, 0.0, 0.4, 0.5);
+    body.detune = 4.0;
+    FmPatch {
+        ops: [
+            op(1.0, 1.0, 0.002, 3.0, 0.0, 0.4, 0.3),
+            op(1.0, 1.3, 0.002, 1.5, 0.1, 0.4, 0.8),
+            body,
+            op(14.0, 1.2, 0.001, 0.15, 0.0, 0.1, 1.0),
+        ],
+        algorithm: 4,
+        feedback: 0.0,
+        gain: 0.35,
+    }
+}
+
+// Inharmonic ratios with a long ring.
+pub fn bell() -> FmPatch {
+    FmPatch {
+        ops: [
+            op(1.0, 1.0, 0.001, 4.0, 0.0, 1.5, 0.3),
+            op(3.5, 2.0, 0.001, 3.0, 0.0, 1.5, 0.7),
+            op(2.0, 0.4, 0.001, 2.0, 0.0, 1.0, 0.3),
+            op(5.19, 1.5, 0.001, 0.8, 0.0, 0.5, 0.8),
+        ],
+        algorithm: 4,
+        feedback: 0.0,
+        gain: 0.3,
+    }
+}
+
+// Brightness swells in with the modulator's slower attack. The self
+// modulating top operator adds the buzz.
+pub fn brass() -> FmPatch {
+    let mut buzz = op(1.0, 0.4, 0.06, 0.3, 0.8, 0.15, 0.4);
+    buzz.detune = 6.0;
+    FmPatch {
+        ops: [
+            op(1.0, 1.0, 0.03, 0.3, 0.85, 0.15, 0.4),
+            op(1.0, 2.2, 0.08, 0.4, 0.6, 0.2, 0.8),
+            buzz,
+            op(1.0, 1.0, 0.08, 0.4, 0.6, 0.2, 0.8),
+        ],
+        algorithm: 4,
+        feedback: 0.6,
+        gain: 0.3,
+    }
+}
+
+// GM programs that the built-in patches stand in for.
+pub fn gm_patches() -> Vec<(RangeInclusive<u8>, FmPatch)> {
+    vec![
+        (4..=5, electric_piano()),
+        (8..=14, bell()),
+        (112..=112, bell()),
+        (56..=63, brass()),
+    ]
+}
+
+// Operator FM synth, DX7 style.
+pub struct Fm {
+    pub patch: FmPatch,
+    pub sample_rate: f64,
+}
+
+impl Fm {
+    pub fn syn(&self, key: u8, velo: u8) -> FmVoice {
+        let freq = 440.0 * 2f64.powf((key as f64 - 69.0) / 12.0);
+        let velo = velo as f64 / 127.0;
+        let ops = self.patch.ops;
+        let algorithm = &ALGORITHMS[self.patch.algorithm % ALGORITHMS.len()];
+        FmVoice {
+            steps: ops.map(|o| {
+                freq * o.ratio * 2f64.powf(o.detune / 1200.0) / self.sample_rate
+            }),
+            levels: ops.map(|o| o.level * (1.0 - o.velo_sens * (1.0 - velo))),
+            envs: ops.map(|o| Some(o.env.make(self.sample_rate))),
+            phases: [0.0; NUM_OPS],
+            feedback: [0.0; 2],
+            ratio: 1.0,
+            algorithm,
+            fb_amount: self.patch.feedback,
+            amp: self.patch.gain / algorithm.carriers.len() as f64,
+        }
+    }
+}
+
+impl Instrument for Fm {
+    fn note_on(&mut self, _ch: u8, key: u8, velo: u8) -> Option<Box<dyn Voice>> {
+        Some(Box::new(self.syn(key, velo)))
+    }
+}
+
+pub struct FmVoice {
+    // Cycles per sample when unbent.
+    steps: [f64; NUM_OPS],
+    levels: [f64; NUM_OPS],
+    // None once finished.
+    envs: [Option<AdsrEnv>; NUM_OPS],
+    phases: [f64; NUM_OPS],
+    // The top operator's last two outputs, averaged to keep feedback stable.
+    feedback: [f64; 2],
+    ratio: f64,
+    algorithm: &'static Algorithm,
+    fb_amount: f64,
+    amp: f64,
+}
+
+impl Voice for FmVoice {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        for o in out.iter_mut() {
+            if self.algorithm.carriers.iter().all(|&c| self.envs[c].is_none()) {
+                return false;
+            }
+            let mut outs = [0.0; NUM_OPS];
+            for i in (0..NUM_OPS).rev() {
+                let env = match self.envs[i].as_mut().and_then(|e| e.next()) {
+                    Some(env) => env as f64,
+                    None => {
+                        self.envs[i] = None;
+                        0.0
+                    }
+                };
+                let mut pm: f64 = self.algorithm.mods.iter()
+                    .filter(|&&(_, to)| to == i)
+                    .map(|&(from, _)| outs[from])
+                    .sum();
+                if i == NUM_OPS - 1 {
+                    pm += self.fb_amount * (self.feedback[0] + self.feedback[1]) / 2.0;
+                }
+                outs[i] = (2.0 * PI * self.phases[i] + pm).sin() * env * self.levels[i];
+                self.phases[i] = (self.phases[i] + self.steps[i] * self.ratio) % 1.0;
+            }
+            self.feedback = [self.feedback[1], outs[NUM_OPS - 1]];
+
+            let v: f64 = self.algorithm.carriers.iter().map(|&c| outs[c]).sum();
+            let v = (v * self.amp) as f32;
+            o[0] += v;
+            o[1] += v;
+        }
+        true
+    }
+
+    fn set_pitch_bend(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    fn release(&mut self) {
+        for env in self.envs.iter_mut().flatten() {
+            env.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 44100.0;
+
+    // A patch of steady operators at the given levels.
+    fn patch(algorithm: usize, levels: [f64; NUM_OPS]) -> FmPatch {
+        FmPatch {
+            ops: levels.map(|level| op(1.0, level, 0.0, 0.0, 1.0, 0.01, 0.0)),
+            algorithm,
+            feedback: 0.0,
+            gain: 1.0,
+        }
+    }
+
+    fn peak(v: &mut FmVoice) -> f32 {
+        let mut out = vec![[0.0; 2]; 1000];
+        v.render(&mut out);
+        out.iter().map(|f| f[0].abs()).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn algorithms_modulate_downwards() {
+        for a in &ALGORITHMS {
+            assert!(a.mods.iter().all(|&(from, to)| from > to && from < NUM_OPS));
+            // Carriers are heard, not used to modulate.
+            assert!(a.carriers.iter().all(|c| a.mods.iter().all(|&(from, _)| from != *c)));
+        }
+    }
+
+    #[test]
+    fn only_carriers_are_heard() {
+        for (ix, a) in ALGORITHMS.iter().enumerate() {
+            for i in 0..NUM_OPS {
+                let mut levels = [0.0; NUM_OPS];
+                levels[i] = 1.0;
+                let fm = Fm { patch: patch(ix, levels), sample_rate: SAMPLE_RATE };
+                let heard = peak(&mut fm.syn(69, 127)) > 0.1;
+                assert_eq!(heard, a.carriers.contains(&i), "algorithm {} operator {}", ix, i);
+            }
+        }
+    }
+
+    #[test]
+    fn ends_with_its_carriers() {
+        // Modulators that sustain, under carriers that decay away.
+        let mut p = patch(4, [1.0, 1.0, 1.0, 1.0]);
+        for i in ALGORITHMS[4].carriers {
+            p.ops[*i].env.decay = 0.1;
+            p.ops[*i].env.sustain = 0.0;
+        }
+        let mut v = Fm { patch: p, sample_rate: SAMPLE_RATE }.syn(69, 127);
+        let mut out = vec![[0.0; 2]; SAMPLE_RATE as usize * 2];
+        assert!(!v.render(&mut out));
+
+        let mut v = Fm { patch: patch(4, [1.0; NUM_OPS]), sample_rate: SAMPLE_RATE }.syn(69, 127);
+        let mut out = vec![[0.0; 2]; SAMPLE_RATE as usize * 2];
+        assert!(v.render(&mut out));
+        v.release();
+        assert!(!v.render(&mut out));
+    }
+}
@@ -0,0 +1,95 @@
+use super::voice::Voice;
+
+// Makes voices for the notes of the programs it is registered for.
+pub trait Instrument: Send {
+    // None if there is nothing to play for the key.
+    fn note_on(&mut self, ch: u8, key: u8, velo: u8) -> Option<Box<dyn Voice>>;
+
+    // The key's voice has been released.
+    fn note_off(&mut self, _ch: u8, _key: u8) {}
+
+    // Sent for every control change, on all channels.
+    fn control_change(&mut self, _ch: u8, _ctrl: u8, _value: u8) {}
+
+    // Back to the initial state, as before the first event.
+    fn reset(&mut self) {}
+
+    // Keys in the same group cut each other off, e.g. open and closed
+    // hi-hats.
+    fn choke_group(&self, _key: u8) -> Option<u8> {
+        None
+    }
+
+    // Whether released notes are strings left to the dampers, which the
+    // damper pedal lifts partly or fully. Otherwise the pedal only holds
+    // notes while at least half down.
+    fn has_dampers(&self) -> bool {
+        false
+    }
+
+    // Whether its notes play through the channel's rotary speaker, see
+    // Leslie.
+    fn has_leslie(&self) -> bool {
+        false
+    }
+}
+
+pub const NUM_PROGRAMS: usize = 128;
+
+// Maps GM programs to instruments. Programs without one of their own play
+// on the fallback.
+pub struct Registry {
+    instruments: Vec<Box<dyn Instrument>>,
+    // Index into instruments, 0 being the fallback.
+    programs: [usize; NUM_PROGRAMS],
+}
+
+impl Registry {
+    pub fn new(fallback: impl Instrument + 'static) -> Self {
+        Self {
+            instruments: vec![Box::new(fallback)],
+            programs: [0; NUM_PROGRAMS],
+        }
+    }
+
+    // Plays the given programs on inst, replacing what they had.
+    pub fn set(&mut self, programs: impl IntoIterator<Item=u8>,
+               inst: impl Instrument + 'static) {
+        self.instruments.push(Box::new(inst));
+        let ix = self.instruments.len() - 1;
+        for p in programs {
+            self.programs[p as usize & 0x7f] = ix;
+        }
+    }
+
+    // Whether the program has an instrument other than the fallback.
+    pub fn has(&self, program: u8) -> bool {
+        self.programs[program as usize & 0x7f] != 0
+    }
+
+    pub fn get(&self, program: u8) -> &dyn Instrument {
+        self.at(self.index_of(program))
+    }
+
+    pub fn get_mut(&mut self, program: u8) -> &mut dyn Instrument {
+        self.at_mut(self.index_of(program))
+    }
+
+    // Index of the program's instrument, which stays valid when programs
+    // are changed.
+    pub fn index_of(&self, program: u8) -> usize {
+        self.programs[program as usize & 0x7f]
+    }
+
+    pub fn at(&self, ix: usize) -> &dyn Instrument {
+        self.instruments[ix].as_ref()
+    }
+
+    pub fn at_mut(&mut self, ix: usize) -> &mut dyn Instrument {
+        self.instruments[ix].as_mut()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=&mut Box<dyn Instrument>> {
+        self.instruments.iter_mut()
+    }
+}
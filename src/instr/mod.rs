@@ -1,5 +1,29 @@
+mod drum_synth;
+mod drums;
+mod fm;
+mod instrument;
+mod organ;
 mod piano;
+mod pluck;
+mod sampler;
+mod sf2;
+mod sfz;
 mod sine;
+mod synth;
+mod voice;
 
-pub use piano::Piano;
-pub use sine::Sine;
+pub use drum_synth::{DrumVoice, synth_kit};
+pub use drums::{DrumKit, Hit, FIRST_DRUM, LAST_DRUM};
+pub use fm::{Fm, FmPatch, FmVoice, Operator, Algorithm, ALGORITHMS, NUM_OPS, gm_patches};
+pub use instrument::{Instrument, Registry, NUM_PROGRAMS};
+pub use organ::{Organ, OrganPreset, Leslie, OrganVoice, Percussion, Scanner, NUM_DRAWBARS, gm_organs};
+pub use piano::{Piano, PianoVoice};
+pub use pluck::{Pluck, PluckPreset, PluckVoice, gm_plucks};
+pub use sine::{Sine, SineInstrument, SineVoice};
+pub use synth::{Synth, SynthPreset, SynthVoice, gm_presets};
+pub use sampler::{SampleVoice, Span, LoopMode, Dahdsr};
+pub use sf2::{SoundFont, Sf2Program};
+pub use sfz::{Sfz, SfzVoice};
+pub use voice::{Voice, Releaser, Layered};
+#[cfg(test)]
+pub(crate) use voice::Tone;
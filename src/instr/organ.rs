@@ -0,0 +1,559 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use crate::types::{Frame, SAMPLE_RATE};
+use crate::soundprim::{Adsr, AdsrEnv, Curve};
+use crate::oscillator::Noise;
+use crate::filter::{Svf, FilterMode};
+use crate::midisyn::NUM_CHANNELS;
+use super::voice::Voice;
+use super::instrument::Instrument;
+
+pub const NUM_DRAWBARS: usize = 9;
+
+// Pitch of each drawbar over the key's: 16', 5 1/3', 8', 4', 2 2/3', 2',
+// 1 3/5', 1 1/3' and 1'.
+const FOOTAGES: [f64; NUM_DRAWBARS] = [0.5, 1.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0];
+
+// Drawbars are set by CCs 70-78, from 16' to 1'.
+const FIRST_DRAWBAR_CC: u8 = 70;
+
+// Range of the tonewheels. Pitches beyond them fold back by octaves.
+const LOWEST_WHEEL: f64 = 32.7;
+const HIGHEST_WHEEL: f64 = 5920.0;
+
+// Rate of the vibrato scanner, Hz.
+const SCANNER_RATE: f64 = 6.9;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Scanner {
+    Off,
+    // Vibrato, and vibrato mixed with the dry sound, from shallow to deep.
+    V1,
+    V2,
+    V3,
+    C1,
+    C2,
+    C3,
+}
+
+impl Scanner {
+    // Peak delay swing in seconds, and whether the dry sound is mixed in.
+    fn depth(self) -> (f64, bool) {
+        match self {
+            Scanner::Off => (0.0, false),
+            Scanner::V1 => (0.00025, false),
+            Scanner::V2 => (0.0005, false),
+            Scanner::V3 => (0.001, false),
+            Scanner::C1 => (0.00025, true),
+            Scanner::C2 => (0.0005, true),
+            Scanner::C3 => (0.001, true),
+        }
+    }
+}
+
+// Decaying 4' or 2 2/3' on the first key of a legato phrase.
+#[derive(Copy, Clone, Debug)]
+pub struct Percussion {
+    pub third: bool,
+    // Seconds
+    pub decay: f64,
+    pub level: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct OrganPreset {
+    // 0 to 8 each.
+    pub drawbars: [u8; NUM_DRAWBARS],
+    pub percussion: Option<Percussion>,
+    pub scanner: Scanner,
+    pub click: f64,
+    // Whether the notes play through the channel's rotary speaker.
+    pub leslie: bool,
+    pub gain: f64,
+}
+
+impl OrganPreset {
+    pub fn default() -> Self {
+        Self {
+            drawbars: [8, 8, 8, 0, 0, 0, 0, 0, 0],
+            percussion: None,
+            scanner: Scanner::C3,
+            click: 0.3,
+            leslie: true,
+            gain: 0.3,
+        }
+    }
+}
+
+// Drawbars written as on the organ, e.g. "888000000".
+fn drawbars(s: &str) -> [u8; NUM_DRAWBARS] {
+    let mut d = [0; NUM_DRAWBARS];
+    for (d, c) in d.iter_mut().zip(s.bytes()) {
+        *d = c.saturating_sub(b'0').min(8);
+    }
+    d
+}
+
+// GM programs that the built-in presets stand in for.
+pub fn gm_organs() -> Vec<(u8, OrganPreset)> {
+    let def = OrganPreset::default;
+    vec![
+        (16, def()),
+        (17, OrganPreset {
+            drawbars: drawbars("888000000"),
+            percussion: Some(Percussion { third: true, decay: 0.2, level: 0.8 }),
+            scanner: Scanner::Off,
+            ..def()
+        }),
+        (18, OrganPreset {
+            drawbars: drawbars("888888888"),
+            percussion: Some(Percussion { third: false, decay: 0.5, level: 0.5 }),
+            click: 0.5,
+            gain: 0.2,
+            ..def()
+        }),
+        (19, OrganPreset {
+            drawbars: drawbars("868606064"),
+            scanner: Scanner::Off,
+            click: 0.0,
+            leslie: false,
+            ..def()
+        }),
+        (20, OrganPreset {
+            drawbars: drawbars("006064000"),
+            scanner: Scanner::V1,
+            click: 0.0,
+            leslie: false,
+            gain: 0.6,
+            ..def()
+        }),
+        (21, OrganPreset {
+            drawbars: drawbars("008888000"),
+            scanner: Scanner::C3,
+            click: 0.0,
+            leslie: false,
+            ..def()
+        }),
+        (22, OrganPreset {
+            drawbars: drawbars("007604000"),
+            scanner: Scanner::V2,
+            click: 0.0,
+            leslie: false,
+            gain: 0.6,
+            ..def()
+        }),
+        (23, OrganPreset {
+            drawbars: drawbars("008808000"),
+            scanner: Scanner::C2,
+            click: 0.0,
+            leslie: false,
+            ..def()
+        }),
+    ]
+}
+
+// Live settings of a channel, that held notes follow.
+struct Controls {
+    drawbars: [AtomicU8; NUM_DRAWBARS],
+}
+
+// Additive tonewheel organ: a sine per drawbar, through a vibrato scanner.
+// The rotary speaker is the channel's, see Leslie.
+pub struct Organ {
+    pub preset: OrganPreset,
+    controls: Arc<[Controls; NUM_CHANNELS]>,
+    // Keys held per channel, for single triggered percussion.
+    held: [u32; NUM_CHANNELS],
+    // Seeds the click noise of the voices.
+    voices: u32,
+}
+
+impl Organ {
+    pub fn new(preset: OrganPreset) -> Self {
+        let controls = Arc::new(std::array::from_fn(|_| Controls {
+            drawbars: preset.drawbars.map(AtomicU8::new),
+        }));
+        Self {
+            preset,
+            controls,
+            held: [0; NUM_CHANNELS],
+            voices: 0,
+        }
+    }
+
+    pub fn syn(&mut self, ch: u8, key: u8, velo: u8) -> OrganVoice {
+        let p = &self.preset;
+        let ch = ch as usize % NUM_CHANNELS;
+        let freq = 440.0 * 2f64.powf((key as f64 - 69.0) / 12.0);
+        let percussion = p.percussion.filter(|_| self.held[ch] == 0).map(|perc| {
+            let ratio = if perc.third { 3.0 } else { 2.0 };
+            let env = Adsr {
+                attack: 0.001,
+                decay: perc.decay,
+                sustain: 0.0,
+                release: 0.01,
+                curve: Curve::Exponential,
+            };
+            (fold(freq * ratio), perc.level, env.make(SAMPLE_RATE))
+        });
+        self.held[ch] += 1;
+        self.voices = self.voices.wrapping_add(1);
+        let env = Adsr {
+            attack: 0.005,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.02,
+            curve: Curve::Linear,
+        };
+
+        OrganVoice {
+            wheels: FOOTAGES.map(|f| (fold(freq * f), 0.0)),
+            percussion,
+            perc_phase: 0.0,
+            controls: self.controls.clone(),
+            ch,
+            env: env.make(SAMPLE_RATE),
+            click: p.click as f32,
+            click_env: click_env(),
+            noise: Noise::new(key as u32 * 7919 + self.voices),
+            click_filter: Svf::new(FilterMode::BandPass, 3000.0, 0.3, SAMPLE_RATE),
+            scanner: p.scanner,
+            scanner_phase: 0.0,
+            scanner_delay: Delay::new(128),
+            ratio: 1.0,
+            amp: (p.gain * (0.6 + 0.4 * velo as f64 / 127.0)) as f32,
+        }
+    }
+}
+
+// Tonewheels only go so low and so high.
+fn fold(mut freq: f64) -> f64 {
+    while freq < LOWEST_WHEEL {
+        freq *= 2.0;
+    }
+    while freq > HIGHEST_WHEEL {
+        freq /= 2.0;
+    }
+    freq
+}
+
+// Drawbar steps are about 3 dB apart.
+fn drawbar_gain(position: u8) -> f64 {
+    match position {
+        0 => 0.0,
+        p => 10f64.powf((p.min(8) as f64 - 8.0) * 3.0 / 20.0),
+    }
+}
+
+// The contacts bouncing, as a key is pressed or released.
+fn click_env() -> AdsrEnv {
+    let adsr = Adsr {
+        attack: 0.0005,
+        decay: 0.004,
+        sustain: 0.0,
+        release: 0.0,
+        curve: Curve::Exponential,
+    };
+    adsr.make(SAMPLE_RATE)
+}
+
+impl Instrument for Organ {
+    fn note_on(&mut self, ch: u8, key: u8, velo: u8) -> Option<Box<dyn Voice>> {
+        Some(Box::new(self.syn(ch, key, velo)))
+    }
+
+    fn note_off(&mut self, ch: u8, _key: u8) {
+        let held = &mut self.held[ch as usize % NUM_CHANNELS];
+        *held = held.saturating_sub(1);
+    }
+
+    fn control_change(&mut self, ch: u8, ctrl: u8, value: u8) {
+        let controls = &self.controls[ch as usize % NUM_CHANNELS];
+        if (FIRST_DRAWBAR_CC..FIRST_DRAWBAR_CC + NUM_DRAWBARS as u8).contains(&ctrl) {
+            // 0-127 onto the 9 positions.
+            let position = ((value as u32 * 8 + 63) / 127) as u8;
+            controls.drawbars[(ctrl - FIRST_DRAWBAR_CC) as usize].store(position, Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&mut self) {
+        for controls in self.controls.iter() {
+            for (d, &p) in controls.drawbars.iter().zip(&self.preset.drawbars) {
+                d.store(p, Ordering::Relaxed);
+            }
+        }
+        self.held = [0; NUM_CHANNELS];
+    }
+
+    fn has_leslie(&self) -> bool {
+        self.preset.leslie
+    }
+}
+
+// A short delay line with fractional taps.
+struct Delay {
+    buf: Vec<f32>,
+    pos: usize,
+}
+
+impl Delay {
+    fn new(len: usize) -> Self {
+        Self { buf: vec![0.0; len], pos: 0 }
+    }
+
+    fn push(&mut self, x: f32) {
+        self.pos = (self.pos + 1) % self.buf.len();
+        self.buf[self.pos] = x;
+    }
+
+    // The sample pushed delay samples ago.
+    fn tap(&self, delay: f64) -> f32 {
+        let n = self.buf.len();
+        let delay = delay.clamp(0.0, (n - 2) as f64);
+        let ix = (self.pos as f64 - delay).rem_euclid(n as f64);
+        let i = ix as usize;
+        let frac = (ix - i as f64) as f32;
+        let (x0, x1) = (self.buf[i], self.buf[(i + 1) % n]);
+        x0 + (x1 - x0) * frac
+    }
+}
+
+// A spinning horn or drum, speeding up and slowing down with inertia.
+struct Rotor {
+    // Cycles
+    phase: f64,
+    // Hz
+    speed: f64,
+    slow: f64,
+    fast: f64,
+    // Seconds to mostly reach a new speed.
+    inertia: f64,
+    // Seconds per sample.
+    dt: f64,
+}
+
+impl Rotor {
+    fn new(slow: f64, fast: f64, inertia: f64, phase: f64, sample_rate: f64) -> Self {
+        Self {
+            phase,
+            speed: slow,
+            slow,
+            fast,
+            inertia,
+            dt: 1.0 / sample_rate,
+        }
+    }
+
+    fn advance(&mut self, fast: bool) {
+        let target = if fast { self.fast } else { self.slow };
+        self.speed += (target - self.speed) * self.dt / self.inertia;
+        self.phase = (self.phase + self.speed * self.dt) % 1.0;
+    }
+
+    fn angle(&self) -> f64 {
+        2.0 * PI * self.phase
+    }
+}
+
+// Rotary speaker: highs from a spinning horn, Doppler shifted and beamed
+// past two mics, and lows from a spinning drum. One per channel, so that
+// the notes of its organs go round together, and the rotors keep their
+// speed from note to note.
+pub struct Leslie {
+    lows: Svf,
+    highs: Svf,
+    horn: Rotor,
+    drum: Rotor,
+    delay: Delay,
+    // Whether the last processed block was heard.
+    ringing: bool,
+}
+
+// Output level below which the speaker has rung out.
+const SILENCE: f32 = 1e-4;
+
+// Delay to the mics, and its swing as the horn turns, in samples.
+const HORN_DELAY: f64 = 44.0;
+const HORN_SWING: f64 = 20.0;
+
+impl Leslie {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            lows: Svf::new(FilterMode::LowPass, 800.0, 0.0, sample_rate),
+            highs: Svf::new(FilterMode::HighPass, 800.0, 0.0, sample_rate),
+            horn: Rotor::new(0.8, 6.8, 0.6, 0.0, sample_rate),
+            drum: Rotor::new(0.7, 5.9, 2.5, 0.37, sample_rate),
+            delay: Delay::new(128),
+            ringing: false,
+        }
+    }
+
+    // Whether what was played through it is still heard.
+    pub fn is_ringing(&self) -> bool {
+        self.ringing
+    }
+
+    // Plays buf through the speaker, the rotors speeding up towards fast or
+    // slowing down.
+    pub fn process(&mut self, buf: &mut [Frame], fast: bool) {
+        let mut peak = 0.0f32;
+        for f in buf.iter_mut() {
+            *f = self.next((f[0] + f[1]) * 0.5, fast);
+            peak = peak.max(f[0].abs()).max(f[1].abs());
+        }
+        self.ringing = peak >= SILENCE;
+    }
+
+    // Turns the rotors for len samples with nothing played through them.
+    pub fn spin(&mut self, fast: bool, len: usize) {
+        for _ in 0..len {
+            self.horn.advance(fast);
+            self.drum.advance(fast);
+        }
+    }
+
+    fn next(&mut self, x: f32, fast: bool) -> Frame {
+        self.horn.advance(fast);
+        self.drum.advance(fast);
+        let (lo, hi) = (self.lows.process(x), self.highs.process(x));
+        self.delay.push(hi);
+
+        // The mics sit on either side, a quarter turn from the front.
+        let (h, d) = (self.horn.angle(), self.drum.angle());
+        let left_hi = self.delay.tap(HORN_DELAY + HORN_SWING * h.sin()) * (0.6 + 0.4 * h.cos()) as f32;
+        let right_hi = self.delay.tap(HORN_DELAY - HORN_SWING * h.sin()) * (0.6 - 0.4 * h.cos()) as f32;
+        let left_lo = lo * (0.8 + 0.2 * d.cos()) as f32;
+        let right_lo = lo * (0.8 - 0.2 * d.cos()) as f32;
+        [left_hi + left_lo, right_hi + right_lo]
+    }
+}
+
+pub struct OrganVoice {
+    // (Hz, phase in cycles) of each drawbar's wheel.
+    wheels: [(f64, f64); NUM_DRAWBARS],
+    // (Hz, level, envelope).
+    percussion: Option<(f64, f64, AdsrEnv)>,
+    perc_phase: f64,
+    controls: Arc<[Controls; NUM_CHANNELS]>,
+    ch: usize,
+    env: AdsrEnv,
+    click: f32,
+    click_env: AdsrEnv,
+    noise: Noise,
+    click_filter: Svf,
+    scanner: Scanner,
+    scanner_phase: f64,
+    scanner_delay: Delay,
+    ratio: f64,
+    amp: f32,
+}
+
+impl Voice for OrganVoice {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        let controls = &self.controls[self.ch];
+        let gains = controls.drawbars.each_ref().map(|d| drawbar_gain(d.load(Ordering::Relaxed)));
+        let (depth, chorus) = self.scanner.depth();
+        let dt = 1.0 / SAMPLE_RATE;
+        let step = self.ratio * dt;
+
+        for o in out.iter_mut() {
+            let env = match self.env.next() {
+                Some(env) => env,
+                None => return false,
+            };
+            let mut x = 0.0;
+            for ((freq, phase), g) in self.wheels.iter_mut().zip(gains) {
+                if g > 0.0 {
+                    x += (2.0 * PI * *phase).sin() * g;
+                }
+                *phase = (*phase + *freq * step) % 1.0;
+            }
+            x /= NUM_DRAWBARS as f64 / 3.0;
+            if let Some((freq, level, perc_env)) = &mut self.percussion {
+                let e = perc_env.next().unwrap_or(0.0) as f64;
+                x += (2.0 * PI * self.perc_phase).sin() * e * *level;
+                self.perc_phase = (self.perc_phase + *freq * step) % 1.0;
+            }
+            let mut x = x as f32 * env;
+
+            if let Some(c) = self.click_env.next() {
+                x += self.click_filter.process(self.noise.next().unwrap_or(0.0)) * c * self.click;
+            }
+
+            if self.scanner != Scanner::Off {
+                self.scanner_delay.push(x);
+                let swing = depth * SAMPLE_RATE;
+                let wet = self.scanner_delay.tap(swing * (1.0 + (2.0 * PI * self.scanner_phase).sin()) + 1.0);
+                self.scanner_phase = (self.scanner_phase + SCANNER_RATE * dt) % 1.0;
+                x = if chorus { 0.5 * (x + wet) } else { wet };
+            }
+
+            o[0] += x * self.amp;
+            o[1] += x * self.amp;
+        }
+        true
+    }
+
+    fn set_pitch_bend(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    fn release(&mut self) {
+        self.env.release();
+        if let Some((_, _, perc_env)) = &mut self.percussion {
+            perc_env.release();
+        }
+        // The contacts click again as they open.
+        self.click_env = click_env();
+        self.click *= 0.5;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn voices_leave_the_leslie_to_the_channel() {
+        let mut organ = Organ::new(OrganPreset::default());
+        assert!(organ.has_leslie());
+        let mut v = organ.note_on(0, 69, 100).unwrap();
+        let mut out = vec![[0.0; 2]; 4410];
+        assert!(v.render(&mut out));
+        assert!(out.iter().any(|f| f[0] != 0.0));
+        assert!(out.iter().all(|f| f[0] == f[1]));
+    }
+
+    #[test]
+    fn leslie_speeds_up_with_inertia() {
+        let mut leslie = Leslie::new(SAMPLE_RATE);
+        let mut buf = vec![[0.0; 2]; 4410];
+        leslie.process(&mut buf, true);
+        // The horn is still getting there, and the heavier drum more so.
+        assert!(leslie.horn.speed > leslie.horn.slow && leslie.horn.speed < leslie.horn.fast);
+        let drum = (leslie.drum.speed - leslie.drum.slow) / (leslie.drum.fast - leslie.drum.slow);
+        let horn = (leslie.horn.speed - leslie.horn.slow) / (leslie.horn.fast - leslie.horn.slow);
+        assert!(drum < horn);
+
+        let mut buf = vec![[0.0; 2]; SAMPLE_RATE as usize * 10];
+        leslie.process(&mut buf, true);
+        assert!((leslie.horn.speed - leslie.horn.fast).abs() < 0.01);
+        assert!((leslie.drum.speed - leslie.drum.fast).abs() < 0.1);
+    }
+
+    #[test]
+    fn leslie_pans_the_horn() {
+        let mut leslie = Leslie::new(SAMPLE_RATE);
+        let mut buf: Vec<Frame> = (0..SAMPLE_RATE as usize * 2)
+            .map(|i| {
+                let x = (2.0 * PI * 3000.0 * i as f64 / SAMPLE_RATE).sin() as f32;
+                [x, x]
+            })
+            .collect();
+        leslie.process(&mut buf, false);
+        assert!(buf.iter().all(|f| f[0].is_finite() && f[1].is_finite()));
+        let diff = buf.iter().map(|f| (f[0] - f[1]).abs()).fold(0.0, f32::max);
+        assert!(diff > 0.1);
+    }
+}
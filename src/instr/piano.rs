@@ -1,15 +1,35 @@
-use crate::types::{R, Sound};
-use crate::soundprim::Envelope;
+use crate::types::{R, Frame};
+use crate::soundprim::{Envelope, frame_at};
 use crate::sample_reader::load_flac;
+use super::voice::{Voice, Releaser};
+use super::instrument::Instrument;
+use crate::midisyn::NUM_CHANNELS;
 use std::collections::HashMap;
 use std::path::Path;
-use std::rc::Rc;
+use std::sync::Arc;
 
-type NoteMap = HashMap<i32, Rc<Vec<f32>>>;
+type NoteMap = HashMap<i32, Arc<Vec<Frame>>>;
 
+// CC 67
+const SOFT_PEDAL: u8 = 67;
+
+// The soft pedal (una corda) shifts the hammers to hit fewer strings with
+// their softer felt: the timbre is that of a softer velocity, but the note
+// is not as much quieter.
+const SOFT_TIMBRE: f64 = 0.6;
+const SOFT_GAIN: f64 = 0.8;
+
+#[derive(Clone)]
 pub struct Piano {
     // 0/1/2: pp, mf, ff
     notes: Vec<NoteMap>,
+
+    // How many semitones a sample may be repitched to stand in for a key
+    // that has none.
+    pub max_stretch: i32,
+
+    // Whether the soft pedal is down, by channel.
+    soft: [bool; NUM_CHANNELS],
 }
 
 const NOTE_NAMES: &'static [&'static str] = &[
@@ -21,43 +41,57 @@ const NOTE_NAMES: &'static [&'static str] = &[
 fn key_to_name(k: i32) -> String {
     // Such that 0 is C1 rather than C4.
     let k = k + 36;
-    let name = NOTE_NAMES[(k % 12) as usize];
-    let nth_octave = 1 + k / 12;
+    let name = NOTE_NAMES[k.rem_euclid(12) as usize];
+    let nth_octave = 1 + k.div_euclid(12);
     format!("{}{}", name, nth_octave)
 }
 
-fn load_normed_flac(base_path: &str, dynamics: &str) -> R<(NoteMap, NoteMap)> {
-    let mut notes0 = NoteMap::new();
-    let mut notes1 = NoteMap::new();
+fn load_normed_flac(base_path: &str, dynamics: &str) -> R<NoteMap> {
+    let mut notes = NoteMap::new();
 
-    for key in -36..48 {
+    // All of MIDI 0-127.
+    for key in -60..68 {
         let path = format!("{}/{}.{}.flac",
                            base_path, key_to_name(key), dynamics);
         if Path::new(&path).exists() {
-            let (ch0, ch1) = load_flac(&path)?;
-            notes0.insert(key, Rc::new(ch0));
-            notes1.insert(key, Rc::new(ch1));
+            let pcm = load_flac(&path)?;
+            let (l, r) = (&pcm.channels[0], pcm.channels.last().unwrap());
+            let frames = l.iter().zip(r).map(|(&l, &r)| [l, r]);
+            notes.insert(key, Arc::new(frames.collect()));
         }
     }
-    Ok((notes0, notes1))
+    Ok(notes)
 }
 
 impl Piano {
-    pub fn load(base_path: &str) -> R<(Self, Self)> {
-        let (pp0, pp1) = load_normed_flac(base_path, "pp")?;
-        let (mf0, mf1) = load_normed_flac(base_path, "mf")?;
-        let (ff0, ff1) = load_normed_flac(base_path, "ff")?;
+    pub fn load(base_path: &str) -> R<Self> {
+        let pp = load_normed_flac(base_path, "pp")?;
+        let mf = load_normed_flac(base_path, "mf")?;
+        let ff = load_normed_flac(base_path, "ff")?;
 
-        Ok((Piano { notes: vec![pp0, mf0, ff0] },
-            Piano { notes: vec![pp1, mf1, ff1] }))
+        Ok(Piano { notes: vec![pp, mf, ff], max_stretch: 24, soft: [false; NUM_CHANNELS] })
     }
 
-    pub fn syn(&self, key: i32, amp: f64) -> impl Sound {
+    // None if there is no sample close enough to the key.
+    pub fn syn(&self, key: i32, amp: f64) -> Option<PianoVoice> {
         self.syn_by_mix(key, amp)
     }
 
+    // The sample of the closest key within max_stretch, and how much
+    // faster it needs to be played.
+    fn nearest(&self, dyn_ix: usize, key: i32) -> Option<Layer> {
+        let notes = &self.notes[dyn_ix];
+        (0..=self.max_stretch)
+            .flat_map(|d| [key - d, key + d])
+            .find_map(|k| notes.get(&k).map(|ss| Layer {
+                ss: ss.clone(),
+                mix: 1.0,
+                stretch: 2f64.powf((key - k) as f64 / 12.0),
+            }))
+    }
+
     #[allow(unused)]
-    fn syn_by_nomix(&self, key: i32, amp: f64) -> impl Sound {
+    fn syn_by_nomix(&self, key: i32, amp: f64) -> Option<PianoVoice> {
         let pp_max = 32.0 / 128.0;
         let mf_max = 80.0 / 128.0;
         let ff_max = 112.0 / 128.0;
@@ -69,18 +103,11 @@ impl Piano {
             1
         };
 
-        let ss = self.notes[dyn_ix][&key].clone();
-
-        let len = ss.len();
-        let dur = len as f64 / 44100.0;
-
-        let mut env = Envelope::fast_release();
-        env.amp = amp;
-        env.mult((0..len).map(move |ix| ss[ix]), dur)
+        PianoVoice::new(self.nearest(dyn_ix, key).into_iter().collect(), amp)
     }
 
     #[allow(unused)]
-    fn syn_by_mix(&self, key: i32, amp: f64) -> impl Sound {
+    fn syn_by_mix(&self, key: i32, amp: f64) -> Option<PianoVoice> {
         // amp 32 80 112
         // ff  1   0
         // mf  0   1   0
@@ -91,10 +118,6 @@ impl Piano {
         let ff_max = 112.0;
         let amp_max = 128.0;
 
-        let pp = self.notes[0][&key].clone();
-        let mf = self.notes[1][&key].clone();
-        let ff = self.notes[2][&key].clone();
-
         let ampf = (amp as f32) * amp_max;
         let pp_amp = if ampf < pp_max {
             1.0
@@ -122,15 +145,109 @@ impl Piano {
             (ampf - ff_max) / (amp_max - ff_max)
         };
 
-        let len = mf.len();
-        let dur = len as f64 / 44100.0;
+        let layers = [(0, pp_amp), (1, mf_amp), (2, ff_amp)].iter()
+            .filter(|&&(_, mix)| mix != 0.0)
+            .filter_map(|&(dyn_ix, mix)| {
+                self.nearest(dyn_ix, key).map(|l| Layer { mix, ..l })
+            })
+            .collect();
+        PianoVoice::new(layers, amp)
+    }
+}
+
+impl Instrument for Piano {
+    fn note_on(&mut self, ch: u8, key: u8, velo: u8) -> Option<Box<dyn Voice>> {
+        let amp = velo as f64 / 128.0;
+        if self.soft[ch as usize % NUM_CHANNELS] {
+            let mut v = self.syn(key as i32 - 60, amp * SOFT_TIMBRE)?;
+            v.env.amp = amp * SOFT_GAIN;
+            return Some(Box::new(v));
+        }
+        let v = self.syn(key as i32 - 60, amp)?;
+        Some(Box::new(v))
+    }
+
+    fn control_change(&mut self, ch: u8, ctrl: u8, value: u8) {
+        if ctrl == SOFT_PEDAL {
+            self.soft[ch as usize % NUM_CHANNELS] = value >= 64;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.soft = [false; NUM_CHANNELS];
+    }
+
+    fn has_dampers(&self) -> bool {
+        true
+    }
+}
+
+struct Layer {
+    ss: Arc<Vec<Frame>>,
+    mix: f32,
+    // Playback speed relative to the voice.
+    stretch: f64,
+}
+
+// Plays the mix of a key's samples, resampled to follow the pitch bend.
+pub struct PianoVoice {
+    layers: Vec<Layer>,
+    // In frames of the voice, i.e. before stretching.
+    len: f64,
+    env: Envelope,
+    pos: f64,
+    ratio: f64,
+    releaser: Releaser,
+}
+
+impl PianoVoice {
+    // The longest layer decides the length.
+    fn new(layers: Vec<Layer>, amp: f64) -> Option<Self> {
+        let len = layers.iter()
+            .map(|l| l.ss.len() as f64 / l.stretch)
+            .reduce(f64::max)?;
 
         let mut env = Envelope::fast_release();
         env.amp = amp;
-        env.mult((0..len).map(move |ix| {
-            ff[ix] * ff_amp + mf[ix] * mf_amp + pp[ix] * pp_amp
-            // ff[ix]
-        }), dur)
+        Some(Self {
+            layers,
+            len,
+            env,
+            pos: 0.0,
+            ratio: 1.0,
+            releaser: Releaser::new(),
+        })
+    }
+}
+
+impl Voice for PianoVoice {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        for o in out.iter_mut() {
+            if self.pos >= self.len {
+                return false;
+            }
+            let gain = match self.releaser.next() {
+                Some(gain) => gain,
+                None => return false,
+            };
+            let env = self.env.level(self.pos / self.len) as f32;
+            for layer in &self.layers {
+                let [l, r] = frame_at(&layer.ss, self.pos * layer.stretch);
+                let amp = layer.mix * env * gain;
+                o[0] += l * amp;
+                o[1] += r * amp;
+            }
+            self.pos += self.ratio;
+        }
+        true
+    }
+
+    fn set_pitch_bend(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    fn release(&mut self) {
+        self.releaser.release();
     }
 }
 
@@ -0,0 +1,368 @@
+use std::ops::RangeInclusive;
+use crate::types::Frame;
+use crate::oscillator::Noise;
+use crate::filter::{Svf, FilterMode};
+use super::voice::Voice;
+use super::instrument::Instrument;
+
+// Lowest pitch bend the delay line has room for.
+const MIN_BEND: f64 = 0.25;
+
+// A voice ends once its level has fallen below this.
+const SILENCE: f32 = 1e-4;
+
+#[derive(Clone, Debug)]
+pub struct PluckPreset {
+    // Seconds for a C4 to fall by 60 dB. Higher notes ring shorter.
+    pub decay: f64,
+    // The same once damped by note-off.
+    pub damped_decay: f64,
+    // Loss of highs on each trip along the string, from 0 (none) to 1.
+    pub damping: f64,
+    // Where the string is plucked, as a fraction of its length from the
+    // bridge. 0 is right at the bridge.
+    pub pick: f64,
+    // Resonances of the body, as (Hz, gain).
+    pub body: Vec<(f64, f64)>,
+    // Soft clipping, 0 for none.
+    pub drive: f64,
+    pub gain: f64,
+}
+
+impl PluckPreset {
+    pub fn default() -> Self {
+        Self {
+            decay: 4.0,
+            damped_decay: 0.08,
+            damping: 0.5,
+            pick: 0.2,
+            body: vec![],
+            drive: 0.0,
+            gain: 0.5,
+        }
+    }
+}
+
+fn nylon_guitar() -> PluckPreset {
+    PluckPreset {
+        damping: 0.7,
+        pick: 0.25,
+        body: vec![(98.0, 0.6), (204.0, 0.4), (390.0, 0.2)],
+        ..PluckPreset::default()
+    }
+}
+
+fn steel_guitar() -> PluckPreset {
+    PluckPreset {
+        decay: 6.0,
+        damping: 0.35,
+        pick: 0.15,
+        body: vec![(110.0, 0.5), (220.0, 0.4), (440.0, 0.2)],
+        ..PluckPreset::default()
+    }
+}
+
+fn electric_guitar() -> PluckPreset {
+    PluckPreset {
+        decay: 8.0,
+        damping: 0.4,
+        pick: 0.1,
+        ..PluckPreset::default()
+    }
+}
+
+fn muted_guitar() -> PluckPreset {
+    PluckPreset {
+        decay: 0.25,
+        damping: 0.9,
+        ..electric_guitar()
+    }
+}
+
+fn overdriven_guitar() -> PluckPreset {
+    PluckPreset {
+        drive: 4.0,
+        gain: 0.3,
+        ..electric_guitar()
+    }
+}
+
+fn harp() -> PluckPreset {
+    PluckPreset {
+        decay: 5.0,
+        damping: 0.6,
+        pick: 0.5,
+        body: vec![(150.0, 0.3)],
+        ..PluckPreset::default()
+    }
+}
+
+fn sitar() -> PluckPreset {
+    PluckPreset {
+        decay: 6.0,
+        damping: 0.1,
+        pick: 0.05,
+        body: vec![(300.0, 0.5), (1200.0, 0.3)],
+        ..PluckPreset::default()
+    }
+}
+
+fn banjo() -> PluckPreset {
+    PluckPreset {
+        decay: 1.5,
+        damping: 0.2,
+        pick: 0.1,
+        body: vec![(400.0, 0.6), (1000.0, 0.4)],
+        ..PluckPreset::default()
+    }
+}
+
+fn kalimba() -> PluckPreset {
+    PluckPreset {
+        decay: 1.5,
+        damping: 0.95,
+        pick: 0.0,
+        body: vec![(350.0, 0.5)],
+        ..PluckPreset::default()
+    }
+}
+
+// GM programs that the built-in presets stand in for.
+pub fn gm_plucks() -> Vec<(RangeInclusive<u8>, PluckPreset)> {
+    vec![
+        (24..=24, nylon_guitar()),
+        (25..=25, steel_guitar()),
+        (26..=27, electric_guitar()),
+        (28..=28, muted_guitar()),
+        (29..=30, overdriven_guitar()),
+        (31..=31, electric_guitar()),
+        (46..=46, harp()),
+        (104..=104, sitar()),
+        (105..=105, banjo()),
+        (106..=107, nylon_guitar()),
+        (108..=108, kalimba()),
+        (109..=110, steel_guitar()),
+    ]
+}
+
+// Karplus-Strong plucked string: a burst of noise circulating in a delay
+// line one period long, losing energy and highs on every trip.
+pub struct Pluck {
+    pub preset: PluckPreset,
+    pub sample_rate: f64,
+    seed: u32,
+}
+
+impl Pluck {
+    pub fn new(preset: PluckPreset, sample_rate: f64) -> Self {
+        Self {
+            preset,
+            sample_rate,
+            seed: 1,
+        }
+    }
+
+    pub fn syn(&mut self, key: u8, velo: u8) -> PluckVoice {
+        let p = &self.preset;
+        let freq = 440.0 * 2f64.powf((key as f64 - 69.0) / 12.0);
+        let velo = velo as f64 / 127.0;
+        let s = 0.5 * p.damping.clamp(0.0, 1.0);
+        let period = self.sample_rate / freq;
+        self.seed = self.seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+
+        let mut v = PluckVoice {
+            buf: vec![0.0; (period / MIN_BEND) as usize + 4],
+            pos: 0,
+            len: 0,
+            ap_coef: 0.0,
+            ap_in: 0.0,
+            ap_out: 0.0,
+            last: 0.0,
+            s,
+            loss: 0.0,
+            damped_loss: t60_loss(p.damped_decay, freq),
+            freq,
+            ratio: 1.0,
+            sample_rate: self.sample_rate,
+            body: p.body.iter().map(|&(f, g)| {
+                (Svf::new(FilterMode::BandPass, f, 0.8, self.sample_rate), g as f32)
+            }).collect(),
+            drive: p.drive as f32,
+            amp: (velo * p.gain) as f32,
+            level: 1.0,
+        };
+        v.loss = t60_loss(p.decay * (261.63 / freq).sqrt(), freq);
+        v.tune();
+
+        // Softer plucks are duller.
+        let mut noise = Noise::new(self.seed);
+        let brightness = (0.1 + 0.9 * velo) as f32;
+        let mut lp = 0.0;
+        let excitation: Vec<f32> = (0..v.len).map(|_| {
+            lp += brightness * (noise.next().unwrap_or(0.0) - lp);
+            lp
+        }).collect();
+        // Plucking at a fraction of the length cancels the harmonics that
+        // have a node there.
+        let pick = (p.pick.clamp(0.0, 0.5) * v.len as f64).round() as usize;
+        let mean = excitation.iter().sum::<f32>() / v.len as f32;
+        let excitation: Vec<f32> = excitation.iter().map(|x| x - mean).collect();
+        for i in 0..v.len {
+            let picked = if pick > 0 && i >= pick { excitation[i - pick] } else { 0.0 };
+            v.buf[i] = excitation[i] - picked;
+        }
+        v.pos = v.len;
+        v
+    }
+}
+
+// Gain per trip along the string for it to fall by 60 dB in t60 seconds.
+fn t60_loss(t60: f64, freq: f64) -> f64 {
+    10f64.powf(-3.0 / (t60.max(0.001) * freq))
+}
+
+impl Instrument for Pluck {
+    fn note_on(&mut self, _ch: u8, key: u8, velo: u8) -> Option<Box<dyn Voice>> {
+        Some(Box::new(self.syn(key, velo)))
+    }
+}
+
+pub struct PluckVoice {
+    // The string, written at pos.
+    buf: Vec<f32>,
+    pos: usize,
+    // Whole samples of delay.
+    len: usize,
+    // First order allpass for the fraction of a sample left over.
+    ap_coef: f64,
+    ap_in: f64,
+    ap_out: f64,
+    // Loop lowpass: (1 - s) x[n] + s x[n - 1].
+    last: f64,
+    s: f64,
+    loss: f64,
+    damped_loss: f64,
+    // Hz, unbent.
+    freq: f64,
+    ratio: f64,
+    sample_rate: f64,
+    body: Vec<(Svf, f32)>,
+    drive: f32,
+    amp: f32,
+    // Peak follower, to know when the string has died down.
+    level: f32,
+}
+
+impl PluckVoice {
+    // Splits the period between the delay line, the loop lowpass (s samples)
+    // and the allpass, which stays stable with a delay in [0.1, 1.1).
+    fn tune(&mut self) {
+        let max = (self.buf.len() - 2) as f64;
+        let delay = (self.sample_rate / (self.freq * self.ratio) - self.s).clamp(2.0, max);
+        let len = (delay - 0.1).floor();
+        let frac = delay - len;
+        self.len = len as usize;
+        self.ap_coef = (1.0 - frac) / (1.0 + frac);
+    }
+}
+
+impl Voice for PluckVoice {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        let n = self.buf.len();
+        for o in out.iter_mut() {
+            if self.level < SILENCE {
+                return false;
+            }
+            let x = self.buf[(self.pos + n - self.len) % n] as f64;
+            let lp = (1.0 - self.s) * x + self.s * self.last;
+            self.last = x;
+            let ap = self.ap_coef * lp + self.ap_in - self.ap_coef * self.ap_out;
+            self.ap_in = lp;
+            self.ap_out = ap;
+            let y = (ap * self.loss) as f32;
+            self.buf[self.pos] = y;
+            self.pos = (self.pos + 1) % n;
+            self.level = (self.level * 0.999).max(y.abs());
+
+            let mut v = y;
+            for (f, g) in self.body.iter_mut() {
+                v += f.process(y) * *g;
+            }
+            if self.drive > 0.0 {
+                v = (v * self.drive).tanh() / self.drive.tanh();
+            }
+            let v = v * self.amp;
+            o[0] += v;
+            o[1] += v;
+        }
+        true
+    }
+
+    fn set_pitch_bend(&mut self, ratio: f64) {
+        self.ratio = ratio.max(MIN_BEND);
+        self.tune();
+    }
+
+    // Damps the string, as by lifting the finger.
+    fn release(&mut self) {
+        self.loss = self.loss.min(self.damped_loss);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopfind::find_period;
+
+    const SAMPLE_RATE: f64 = 44100.0;
+
+    fn render(v: &mut PluckVoice, secs: f64) -> Vec<f32> {
+        let mut out = vec![[0.0; 2]; (SAMPLE_RATE * secs) as usize];
+        v.render(&mut out);
+        out.iter().map(|f| f[0]).collect()
+    }
+
+    // How far off the rendered pitch is from freq, in cents.
+    fn cents_off(v: &mut PluckVoice, freq: f64) -> f64 {
+        render(v, 0.1);
+        let ss = render(v, 0.2);
+        let period = find_period(&ss, 20, 1000).unwrap();
+        1200.0 * (SAMPLE_RATE / freq / period).log2()
+    }
+
+    #[test]
+    fn plays_in_tune() {
+        for key in [45, 57, 69, 81] {
+            let mut pluck = Pluck::new(PluckPreset::default(), SAMPLE_RATE);
+            let freq = 440.0 * 2f64.powf((key as f64 - 69.0) / 12.0);
+            let off = cents_off(&mut pluck.syn(key, 100), freq);
+            assert!(off.abs() < 3.0, "key {} off by {} cents", key, off);
+        }
+    }
+
+    #[test]
+    fn bends_in_tune() {
+        for semitones in [-12.0, -2.0, 2.0, 7.0] {
+            let mut pluck = Pluck::new(PluckPreset::default(), SAMPLE_RATE);
+            let mut v = pluck.syn(57, 100);
+            let ratio = 2f64.powf(semitones / 12.0);
+            v.set_pitch_bend(ratio);
+            let off = cents_off(&mut v, 220.0 * ratio);
+            assert!(off.abs() < 3.0, "bent {} off by {} cents", semitones, off);
+        }
+    }
+
+    #[test]
+    fn release_damps_the_string() {
+        let energy = |ss: &[f32]| ss.iter().map(|x| x * x).sum::<f32>();
+        let mut held = Pluck::new(PluckPreset::default(), SAMPLE_RATE).syn(57, 100);
+        let mut released = Pluck::new(PluckPreset::default(), SAMPLE_RATE).syn(57, 100);
+        render(&mut held, 0.1);
+        render(&mut released, 0.1);
+        released.release();
+        render(&mut held, 0.1);
+        render(&mut released, 0.1);
+        let (held, released) = (render(&mut held, 0.1), render(&mut released, 0.1));
+        assert!(energy(&released) < energy(&held) * 1e-4);
+    }
+}
@@ -0,0 +1,304 @@
+use crate::types::{Frame, SAMPLE_RATE};
+use crate::soundprim::sample_at;
+use super::voice::Voice;
+use std::sync::Arc;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LoopMode {
+    NoLoop,
+    // Loop forever, even after release.
+    Continuous,
+    // Loop while the key is held, then play on to the end.
+    UntilRelease,
+    // No loop, and play to the end even after release.
+    OneShot,
+}
+
+// Which part of the sample data to play, and how to loop it. In indices
+// into the data.
+#[derive(Copy, Clone, Debug)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub mode: LoopMode,
+    // Play the loop back and forth rather than jumping back to its start.
+    pub ping_pong: bool,
+    // Number of samples before loop_end that fade into those before
+    // loop_start, to hide the jump. Only for forward loops.
+    pub crossfade: usize,
+}
+
+// Delay, attack, hold, decay, sustain, release volume envelope. Times are
+// in seconds. Attack is linear, decay and release fall 100 dB over their
+// time, as in SoundFonts.
+#[derive(Copy, Clone, Debug)]
+pub struct Dahdsr {
+    pub delay: f64,
+    pub attack: f64,
+    pub hold: f64,
+    pub decay: f64,
+    // Level in dB, 0 or less.
+    pub sustain: f64,
+    pub release: f64,
+}
+
+impl Dahdsr {
+    pub fn default() -> Self {
+        Self {
+            delay: 0.0,
+            attack: 0.001,
+            hold: 0.0,
+            decay: 0.001,
+            sustain: 0.0,
+            release: 0.1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Stage {
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Release,
+}
+
+const FLOOR_DB: f64 = -100.0;
+
+struct VolEnv {
+    env: Dahdsr,
+    stage: Stage,
+    // Seconds into the stage.
+    t: f64,
+    db: f64,
+}
+
+impl VolEnv {
+    fn new(env: Dahdsr) -> Self {
+        Self { env, stage: Stage::Delay, t: 0.0, db: FLOOR_DB }
+    }
+
+    fn release(&mut self) {
+        if self.stage != Stage::Release {
+            self.stage = Stage::Release;
+            self.t = 0.0;
+        }
+    }
+
+    // Gain for the next sample, None once faded out.
+    fn next(&mut self) -> Option<f32> {
+        use self::Stage::*;
+
+        let dt = 1.0 / SAMPLE_RATE;
+        let gain = match self.stage {
+            Delay | Hold if self.t >= self.stage_len() => {
+                self.stage = if self.stage == Delay { Attack } else { Decay };
+                self.t = 0.0;
+                return self.next();
+            }
+            Attack if self.t >= self.env.attack => {
+                self.stage = Hold;
+                self.t = 0.0;
+                self.db = 0.0;
+                return self.next();
+            }
+            Delay => 0.0,
+            Attack => {
+                let level = self.t / self.env.attack;
+                self.db = 20.0 * level.log10();
+                level
+            }
+            Hold => 1.0,
+            Decay => {
+                let step = FLOOR_DB / self.env.decay.max(dt) * dt;
+                self.db = (self.db + step).max(self.env.sustain);
+                if self.db <= FLOOR_DB {
+                    return None;
+                }
+                db_to_gain(self.db)
+            }
+            Release => {
+                let step = FLOOR_DB / self.env.release.max(dt) * dt;
+                self.db += step;
+                if self.db <= FLOOR_DB {
+                    return None;
+                }
+                db_to_gain(self.db)
+            }
+        };
+        self.t += dt;
+        Some(gain as f32)
+    }
+
+    fn stage_len(&self) -> f64 {
+        match self.stage {
+            Stage::Delay => self.env.delay,
+            _ => self.env.hold,
+        }
+    }
+}
+
+pub fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+// Plays a span of mono sample data, looping it if asked to.
+pub struct SampleVoice {
+    data: Arc<Vec<f32>>,
+    span: Span,
+    pos: f64,
+    // Going backwards through a ping-pong loop.
+    backwards: bool,
+    // Playback speed when unbent, including the sample rate conversion.
+    step: f64,
+    ratio: f64,
+    // (left, right)
+    gains: (f32, f32),
+    env: VolEnv,
+    released: bool,
+}
+
+impl SampleVoice {
+    pub fn new(data: Arc<Vec<f32>>, span: Span, step: f64,
+               gains: (f32, f32), env: Dahdsr) -> Self {
+        let mut span = span;
+        span.end = span.end.min(data.len());
+        let bad_loop = span.loop_start >= span.loop_end || span.loop_end > span.end;
+        if bad_loop && span.mode != LoopMode::OneShot {
+            span.mode = LoopMode::NoLoop;
+        }
+        // The fade in comes from before the loop.
+        span.crossfade = span.crossfade
+            .min(span.loop_end.saturating_sub(span.loop_start))
+            .min(span.loop_start.saturating_sub(span.start));
+        Self {
+            pos: span.start as f64,
+            backwards: false,
+            data,
+            span,
+            step,
+            ratio: 1.0,
+            gains,
+            env: VolEnv::new(env),
+            released: false,
+        }
+    }
+
+    fn looping(&self) -> bool {
+        match self.span.mode {
+            LoopMode::NoLoop | LoopMode::OneShot => false,
+            LoopMode::Continuous => true,
+            LoopMode::UntilRelease => !self.released,
+        }
+    }
+
+    fn sample(&self) -> f32 {
+        let v = sample_at(&self.data, self.pos);
+        let fade_start = (self.span.loop_end - self.span.crossfade) as f64;
+        if self.span.crossfade == 0 || self.span.ping_pong || self.pos < fade_start
+            || !self.looping() {
+            return v;
+        }
+        let loop_len = (self.span.loop_end - self.span.loop_start) as f64;
+        let fade_in = ((self.pos - fade_start) / self.span.crossfade as f64) as f32;
+        v * (1.0 - fade_in) + sample_at(&self.data, self.pos - loop_len) * fade_in
+    }
+
+    fn advance(&mut self) {
+        let step = self.step * self.ratio;
+        let looping = self.looping();
+        let loop_start = self.span.loop_start as f64;
+        let loop_end = self.span.loop_end as f64;
+        if self.backwards && looping {
+            self.pos -= step;
+            if self.pos <= loop_start {
+                self.pos = 2.0 * loop_start - self.pos;
+                self.backwards = false;
+            }
+            return;
+        }
+        // Released in the middle of going backwards: play on to the end.
+        self.backwards = false;
+        self.pos += step;
+        if looping && self.pos >= loop_end {
+            if self.span.ping_pong {
+                // Turn around at the last sample of the loop.
+                self.pos = 2.0 * (loop_end - 1.0) - self.pos;
+                self.backwards = true;
+            } else if loop_end > loop_start {
+                // Steps can be longer than tiny loops.
+                self.pos = loop_start + (self.pos - loop_start).rem_euclid(loop_end - loop_start);
+            }
+        }
+    }
+}
+
+impl Voice for SampleVoice {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        for o in out.iter_mut() {
+            if self.pos >= self.span.end as f64 {
+                return false;
+            }
+            let gain = match self.env.next() {
+                Some(gain) => gain,
+                None => return false,
+            };
+            let v = self.sample() * gain;
+            o[0] += v * self.gains.0;
+            o[1] += v * self.gains.1;
+            self.advance();
+        }
+        true
+    }
+
+    fn set_pitch_bend(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    fn release(&mut self) {
+        if self.span.mode == LoopMode::OneShot {
+            return;
+        }
+        self.released = true;
+        self.env.release();
+    }
+
+    fn choke(&mut self) {
+        self.released = true;
+        self.env.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A two sample loop played 8 times faster steps over it whole.
+    fn tiny_loop(crossfade: usize) -> SampleVoice {
+        let data = Arc::new(vec![0.0, 0.5, -0.5, 0.0, 0.0]);
+        let span = Span {
+            start: 0,
+            end: 5,
+            loop_start: 1,
+            loop_end: 3,
+            mode: LoopMode::Continuous,
+            ping_pong: false,
+            crossfade,
+        };
+        SampleVoice::new(data, span, 8.5, (1.0, 1.0), Dahdsr::default())
+    }
+
+    #[test]
+    fn tiny_loop_pitched_up_stays_in_loop() {
+        for crossfade in [0, 1] {
+            let mut v = tiny_loop(crossfade);
+            let mut out = vec![[0.0; 2]; 1000];
+            assert!(v.render(&mut out));
+            assert!(out.iter().all(|f| f[0].is_finite() && f[0].abs() <= 1.0));
+            assert!(v.pos >= 1.0 && v.pos < 3.0, "{}", v.pos);
+        }
+    }
+}
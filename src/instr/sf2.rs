@@ -0,0 +1,537 @@
+use crate::types::{R, SAMPLE_RATE};
+use crate::sample_reader::riff_chunks;
+use crate::soundprim::pan_gains;
+use super::sampler::{SampleVoice, Span, LoopMode, Dahdsr};
+use super::voice::{Voice, Layered};
+use super::instrument::{Instrument, Registry};
+use crate::midisyn::NUM_CHANNELS;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+// Generator operators that we make use of. See the SoundFont 2.04 spec,
+// section 8.1.2.
+const START_ADDRS_OFFSET: usize = 0;
+const END_ADDRS_OFFSET: usize = 1;
+const STARTLOOP_ADDRS_OFFSET: usize = 2;
+const ENDLOOP_ADDRS_OFFSET: usize = 3;
+const START_ADDRS_COARSE_OFFSET: usize = 4;
+const END_ADDRS_COARSE_OFFSET: usize = 12;
+const PAN: usize = 17;
+const DELAY_VOL_ENV: usize = 33;
+const ATTACK_VOL_ENV: usize = 34;
+const HOLD_VOL_ENV: usize = 35;
+const DECAY_VOL_ENV: usize = 36;
+const SUSTAIN_VOL_ENV: usize = 37;
+const RELEASE_VOL_ENV: usize = 38;
+const KEYNUM_TO_VOL_ENV_HOLD: usize = 39;
+const KEYNUM_TO_VOL_ENV_DECAY: usize = 40;
+const INSTRUMENT: usize = 41;
+const KEY_RANGE: usize = 43;
+const VEL_RANGE: usize = 44;
+const STARTLOOP_ADDRS_COARSE_OFFSET: usize = 45;
+const KEYNUM: usize = 46;
+const VELOCITY: usize = 47;
+const INITIAL_ATTENUATION: usize = 48;
+const ENDLOOP_ADDRS_COARSE_OFFSET: usize = 50;
+const COARSE_TUNE: usize = 51;
+const FINE_TUNE: usize = 52;
+const SAMPLE_ID: usize = 53;
+const SAMPLE_MODES: usize = 54;
+const SCALE_TUNING: usize = 56;
+const OVERRIDING_ROOT_KEY: usize = 58;
+const NUM_GENS: usize = 61;
+
+// Raw generator amounts of a zone.
+type Gens = [Option<u16>; NUM_GENS];
+
+// Generators that only make sense at the instrument level, and so are not
+// added from preset zones.
+fn is_instrument_only(g: usize) -> bool {
+    matches!(g, START_ADDRS_OFFSET..=START_ADDRS_COARSE_OFFSET
+             | END_ADDRS_COARSE_OFFSET
+             | STARTLOOP_ADDRS_COARSE_OFFSET..=VELOCITY
+             | ENDLOOP_ADDRS_COARSE_OFFSET
+             | SAMPLE_ID..=OVERRIDING_ROOT_KEY)
+}
+
+fn default_gen(g: usize) -> i32 {
+    match g {
+        // Envelope times, in timecents: about a millisecond.
+        DELAY_VOL_ENV..=DECAY_VOL_ENV | RELEASE_VOL_ENV => -12000,
+        SCALE_TUNING => 100,
+        KEYNUM | VELOCITY | OVERRIDING_ROOT_KEY => -1,
+        _ => 0,
+    }
+}
+
+fn u16_at(bs: &[u8], ix: usize) -> u16 {
+    u16::from_le_bytes([bs[ix], bs[ix + 1]])
+}
+
+fn u32_at(bs: &[u8], ix: usize) -> u32 {
+    u32::from_le_bytes([bs[ix], bs[ix + 1], bs[ix + 2], bs[ix + 3]])
+}
+
+// (lo, hi) of a range generator, both inclusive.
+fn range_of(gens: &Gens, g: usize) -> (u8, u8) {
+    match gens[g] {
+        Some(amount) => {
+            let [lo, hi] = amount.to_le_bytes();
+            (lo, hi)
+        }
+        None => (0, 127),
+    }
+}
+
+fn intersect(a: (u8, u8), b: (u8, u8)) -> Option<(u8, u8)> {
+    let r = (a.0.max(b.0), a.1.min(b.1));
+    if r.0 <= r.1 {
+        Some(r)
+    } else {
+        None
+    }
+}
+
+fn timecents_to_secs(tc: i32) -> f64 {
+    2f64.powf(tc as f64 / 1200.0)
+}
+
+struct SampleHeader {
+    start: i64,
+    end: i64,
+    loop_start: i64,
+    loop_end: i64,
+    sample_rate: f64,
+    original_pitch: u8,
+    // In cents.
+    pitch_correction: i8,
+    sample_type: u16,
+}
+
+const RIGHT_SAMPLE: u16 = 2;
+const LEFT_SAMPLE: u16 = 4;
+// Set on samples that live in a ROM of the sound card.
+const ROM_SAMPLE: u16 = 0x8000;
+
+// The sample and the resolved generators to play for a range of keys and
+// velocities.
+struct Region {
+    keys: (u8, u8),
+    vels: (u8, u8),
+    sample: usize,
+    gens: [i32; NUM_GENS],
+}
+
+impl Region {
+    fn matches(&self, key: u8, velo: u8) -> bool {
+        (self.keys.0..=self.keys.1).contains(&key)
+            && (self.vels.0..=self.vels.1).contains(&velo)
+    }
+}
+
+// The hydra: the preset, instrument and sample records in the pdta chunk.
+#[derive(Default)]
+struct Hydra<'a> {
+    phdr: &'a [u8],
+    pbag: &'a [u8],
+    pgen: &'a [u8],
+    inst: &'a [u8],
+    ibag: &'a [u8],
+    igen: &'a [u8],
+    shdr: &'a [u8],
+}
+
+impl<'a> Hydra<'a> {
+    fn new(pdta: &'a [u8]) -> Self {
+        let mut h = Hydra::default();
+        for (id, body) in riff_chunks(pdta) {
+            match id {
+                b"phdr" => h.phdr = body,
+                b"pbag" => h.pbag = body,
+                b"pgen" => h.pgen = body,
+                b"inst" => h.inst = body,
+                b"ibag" => h.ibag = body,
+                b"igen" => h.igen = body,
+                b"shdr" => h.shdr = body,
+                _ => {},
+            }
+        }
+        h
+    }
+
+    // Zones of bags [first, last), as found in bag and gen records.
+    fn zones(bags: &[u8], gens: &[u8], first: usize, last: usize) -> Vec<Gens> {
+        let num_gens = gens.len() / 4;
+        (first..last).filter(|b| (b + 1) * 4 + 4 <= bags.len()).map(|b| {
+            let mut zone = [None; NUM_GENS];
+            let gen_start = u16_at(bags, b * 4) as usize;
+            let gen_end = (u16_at(bags, b * 4 + 4) as usize).min(num_gens);
+            for g in gen_start..gen_end {
+                let oper = u16_at(gens, g * 4) as usize;
+                if oper < NUM_GENS {
+                    zone[oper] = Some(u16_at(gens, g * 4 + 2));
+                }
+            }
+            zone
+        }).collect()
+    }
+
+    // Instrument zones of the nth instrument, the global one merged in.
+    fn instrument_zones(&self, n: usize) -> Vec<Gens> {
+        if (n + 2) * 22 > self.inst.len() {
+            return vec![];
+        }
+        let first = u16_at(self.inst, n * 22 + 20) as usize;
+        let last = u16_at(self.inst, n * 22 + 42) as usize;
+        split_global(Self::zones(self.ibag, self.igen, first, last), SAMPLE_ID)
+    }
+
+    // (bank, program) and the zones of each preset.
+    fn presets(&self) -> Vec<((u8, u8), Vec<Gens>)> {
+        // The last record only terminates the list.
+        let num_presets = (self.phdr.len() / 38).saturating_sub(1);
+        (0..num_presets).map(|n| {
+            let rec = &self.phdr[n * 38..];
+            let program = u16_at(rec, 20) as u8;
+            let bank = u16_at(rec, 22) as u8;
+            let first = u16_at(rec, 24) as usize;
+            let last = u16_at(rec, 38 + 24) as usize;
+            let zones = split_global(Self::zones(self.pbag, self.pgen, first, last), INSTRUMENT);
+            ((bank, program), zones)
+        }).collect()
+    }
+
+    fn samples(&self) -> Vec<SampleHeader> {
+        self.shdr.chunks_exact(46).map(|rec| SampleHeader {
+            start: u32_at(rec, 20) as i64,
+            end: u32_at(rec, 24) as i64,
+            loop_start: u32_at(rec, 28) as i64,
+            loop_end: u32_at(rec, 32) as i64,
+            sample_rate: u32_at(rec, 36) as f64,
+            original_pitch: rec[40],
+            pitch_correction: rec[41] as i8,
+            sample_type: u16_at(rec, 44),
+        }).collect()
+    }
+}
+
+// Drops the global zone, which is the first zone if it lacks the given
+// terminal generator, merging it into the other zones.
+fn split_global(mut zones: Vec<Gens>, terminal: usize) -> Vec<Gens> {
+    if zones.first().is_some_and(|z| z[terminal].is_none()) {
+        let global = zones.remove(0);
+        for z in &mut zones {
+            for (g, v) in z.iter_mut().enumerate() {
+                if v.is_none() {
+                    *v = global[g];
+                }
+            }
+        }
+    }
+    zones.retain(|z| z[terminal].is_some());
+    zones
+}
+
+// An SF2 SoundFont, with its presets flattened into regions.
+pub struct SoundFont {
+    // All sample data, mono. Sample headers index into it.
+    data: Arc<Vec<f32>>,
+    samples: Vec<SampleHeader>,
+    // Keyed by (bank, program).
+    presets: HashMap<(u8, u8), Vec<Region>>,
+}
+
+impl SoundFont {
+    pub fn load(path: &str) -> R<Self> {
+        let bytes = fs::read(path)?;
+        Self::parse(&bytes).map_err(|e| format!("{}: {}", path, e).into())
+    }
+
+    // From the contents of an SF2 file.
+    pub fn parse(bytes: &[u8]) -> R<Self> {
+        let riff = match riff_chunks(bytes).first() {
+            Some((b"RIFF", body)) if body.starts_with(b"sfbk") => &body[4..],
+            _ => return Err("not a SoundFont".into()),
+        };
+
+        let mut smpl: &[u8] = &[];
+        let mut pdta: &[u8] = &[];
+        for (id, body) in riff_chunks(riff) {
+            if id != b"LIST" || body.len() < 4 {
+                continue;
+            }
+            match &body[..4] {
+                b"sdta" => {
+                    for (id, body) in riff_chunks(&body[4..]) {
+                        if id == b"smpl" {
+                            smpl = body;
+                        }
+                    }
+                }
+                b"pdta" => pdta = &body[4..],
+                _ => {},
+            }
+        }
+
+        let data = smpl.chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+            .collect();
+        let hydra = Hydra::new(pdta);
+        let samples = hydra.samples();
+
+        let mut presets = HashMap::new();
+        for (id, zones) in hydra.presets() {
+            let mut regions = vec![];
+            for pz in zones {
+                let inst = pz[INSTRUMENT].unwrap() as usize;
+                for iz in hydra.instrument_zones(inst) {
+                    let sample = iz[SAMPLE_ID].unwrap() as usize;
+                    match samples.get(sample) {
+                        Some(s) if s.sample_type & ROM_SAMPLE == 0 => {},
+                        _ => continue,
+                    }
+                    let keys = intersect(range_of(&pz, KEY_RANGE), range_of(&iz, KEY_RANGE));
+                    let vels = intersect(range_of(&pz, VEL_RANGE), range_of(&iz, VEL_RANGE));
+                    if let (Some(keys), Some(vels)) = (keys, vels) {
+                        let gens = std::array::from_fn(|g| {
+                            let value = iz[g].map_or(default_gen(g), |v| v as i16 as i32);
+                            match pz[g] {
+                                Some(v) if !is_instrument_only(g) => value + v as i16 as i32,
+                                _ => value,
+                            }
+                        });
+                        regions.push(Region { keys, vels, sample, gens });
+                    }
+                }
+            }
+            // The first preset wins if there are duplicates.
+            presets.entry(id).or_insert(regions);
+        }
+
+        Ok(Self { data: Arc::new(data), samples, presets })
+    }
+
+    // Plays every GM program that this SoundFont has from it, replacing
+    // what they had.
+    pub fn register(self, registry: &mut Registry) {
+        let sf = Arc::new(self);
+        for program in 0..128 {
+            if sf.has_preset(0, program) {
+                registry.set([program], Sf2Program {
+                    sf: sf.clone(),
+                    program,
+                    banks: [0; NUM_CHANNELS],
+                });
+            }
+        }
+    }
+
+    // Missing banks fall back to bank 0, as in GM.
+    fn preset(&self, bank: u8, program: u8) -> Option<&[Region]> {
+        self.presets.get(&(bank, program))
+            .or_else(|| self.presets.get(&(0, program)))
+            .map(|rs| rs.as_slice())
+    }
+
+    pub fn has_preset(&self, bank: u8, program: u8) -> bool {
+        self.preset(bank, program).is_some()
+    }
+
+    pub fn syn(&self, bank: u8, program: u8, key: u8, velo: u8) -> Option<Layered<SampleVoice>> {
+        let voices: Vec<_> = self.preset(bank, program)?.iter()
+            .filter(|r| r.matches(key, velo))
+            .map(|r| self.voice(r, key, velo))
+            .collect();
+        if voices.is_empty() {
+            None
+        } else {
+            Some(Layered(voices))
+        }
+    }
+
+    fn voice(&self, r: &Region, key: u8, velo: u8) -> SampleVoice {
+        let g = &r.gens;
+        let s = &self.samples[r.sample];
+
+        let addr = |base: i64, fine: usize, coarse: usize| {
+            (base + g[fine] as i64 + g[coarse] as i64 * 32768).max(0) as usize
+        };
+        let mode = match g[SAMPLE_MODES] & 3 {
+            1 => LoopMode::Continuous,
+            3 => LoopMode::UntilRelease,
+            _ => LoopMode::NoLoop,
+        };
+        let span = Span {
+            start: addr(s.start, START_ADDRS_OFFSET, START_ADDRS_COARSE_OFFSET),
+            end: addr(s.end, END_ADDRS_OFFSET, END_ADDRS_COARSE_OFFSET),
+            loop_start: addr(s.loop_start, STARTLOOP_ADDRS_OFFSET, STARTLOOP_ADDRS_COARSE_OFFSET),
+            loop_end: addr(s.loop_end, ENDLOOP_ADDRS_OFFSET, ENDLOOP_ADDRS_COARSE_OFFSET),
+            mode,
+            ping_pong: false,
+            crossfade: 0,
+        };
+
+        let key = if g[KEYNUM] >= 0 { g[KEYNUM] } else { key as i32 };
+        let velo = if g[VELOCITY] >= 0 { g[VELOCITY] } else { velo as i32 };
+        let root = if g[OVERRIDING_ROOT_KEY] >= 0 {
+            g[OVERRIDING_ROOT_KEY]
+        } else {
+            s.original_pitch as i32
+        };
+        let cents = (key - root) * g[SCALE_TUNING]
+            + g[COARSE_TUNE] * 100
+            + g[FINE_TUNE]
+            + s.pitch_correction as i32;
+        let step = 2f64.powf(cents as f64 / 1200.0) * s.sample_rate / SAMPLE_RATE;
+
+        // Attenuation is in centibels. Velocity follows the default
+        // concave curve of the spec, roughly squared.
+        let attenuation = 10f32.powf(-g[INITIAL_ATTENUATION].max(0) as f32 / 200.0);
+        let gain = attenuation * (velo as f32 / 127.0).powi(2);
+        let pan = match (g[PAN], s.sample_type) {
+            (0, LEFT_SAMPLE) => -500,
+            (0, RIGHT_SAMPLE) => 500,
+            (pan, _) => pan,
+        };
+        let (l, r) = pan_gains((pan + 500) as f32 / 1000.0);
+
+        let key_scaling = |g_ix: usize| (g[g_ix] * (60 - key)) as f64 / 1200.0;
+        let env = Dahdsr {
+            delay: timecents_to_secs(g[DELAY_VOL_ENV]),
+            attack: timecents_to_secs(g[ATTACK_VOL_ENV]),
+            hold: timecents_to_secs(g[HOLD_VOL_ENV]) * 2f64.powf(key_scaling(KEYNUM_TO_VOL_ENV_HOLD)),
+            decay: timecents_to_secs(g[DECAY_VOL_ENV]) * 2f64.powf(key_scaling(KEYNUM_TO_VOL_ENV_DECAY)),
+            sustain: -(g[SUSTAIN_VOL_ENV].clamp(0, 1000) as f64) / 10.0,
+            release: timecents_to_secs(g[RELEASE_VOL_ENV]),
+        };
+
+        SampleVoice::new(self.data.clone(), span, step, (l * gain, r * gain), env)
+    }
+}
+
+// One program of a SoundFont, following the bank selects of each channel.
+pub struct Sf2Program {
+    sf: Arc<SoundFont>,
+    program: u8,
+    banks: [u8; NUM_CHANNELS],
+}
+
+impl Instrument for Sf2Program {
+    fn note_on(&mut self, ch: u8, key: u8, velo: u8) -> Option<Box<dyn Voice>> {
+        let bank = self.banks[ch as usize];
+        let v = self.sf.syn(bank, self.program, key, velo)?;
+        Some(Box::new(v))
+    }
+
+    fn control_change(&mut self, ch: u8, ctrl: u8, value: u8) {
+        // Bank select MSB.
+        if ctrl == 0 {
+            self.banks[ch as usize] = value;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.banks = [0; NUM_CHANNELS];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn list(kind: &[u8], chunks: &[Vec<u8>]) -> Vec<u8> {
+        chunk(b"LIST", &[kind.to_vec(), chunks.concat()].concat())
+    }
+
+    fn u16s(xs: &[u16]) -> Vec<u8> {
+        xs.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    // A name followed by fields.
+    fn record(fields: &[u8]) -> Vec<u8> {
+        [vec![0; 20], fields.to_vec()].concat()
+    }
+
+    fn range(lo: u8, hi: u8) -> u16 {
+        u16::from_le_bytes([lo, hi])
+    }
+
+    fn shdr(start: u32, end: u32, loop_start: u32, loop_end: u32, pitch: u8) -> Vec<u8> {
+        let mut fields: Vec<u8> = [start, end, loop_start, loop_end, 44100]
+            .iter().flat_map(|x| x.to_le_bytes()).collect();
+        fields.extend_from_slice(&[pitch, 0]);
+        // Sample link, mono sample.
+        fields.extend_from_slice(&u16s(&[0, 1]));
+        record(&fields)
+    }
+
+    // Program 5 of bank 0: a global preset zone limiting keys to 60-72 over
+    // an instrument with a one-shot sample below 65 and a looped one above.
+    fn font() -> Vec<u8> {
+        let smpl: Vec<u8> = (0..246)
+            .map(|i| if i < 200 { ((i as f32 * 0.3).sin() * 16000.0) as i16 } else { 0 })
+            .flat_map(|x| x.to_le_bytes())
+            .collect();
+        let phdr = [
+            record(&[u16s(&[5, 0, 0]), vec![0; 12]].concat()),
+            record(&[u16s(&[0, 0, 2]), vec![0; 12]].concat()),
+        ].concat();
+        let inst = [record(&u16s(&[0])), record(&u16s(&[2]))].concat();
+        let pdta = list(b"pdta", &[
+            chunk(b"phdr", &phdr),
+            chunk(b"pbag", &u16s(&[0, 0, 1, 0, 2, 0])),
+            chunk(b"pgen", &u16s(&[KEY_RANGE as u16, range(60, 72), INSTRUMENT as u16, 0, 0, 0])),
+            chunk(b"inst", &inst),
+            chunk(b"ibag", &u16s(&[0, 0, 2, 0, 5, 0])),
+            chunk(b"igen", &u16s(&[
+                KEY_RANGE as u16, range(0, 64), SAMPLE_ID as u16, 0,
+                KEY_RANGE as u16, range(65, 127), SAMPLE_MODES as u16, 1, SAMPLE_ID as u16, 1,
+                0, 0,
+            ])),
+            chunk(b"shdr", &[shdr(0, 100, 0, 0, 60), shdr(100, 200, 120, 180, 72), shdr(0, 0, 0, 0, 0)].concat()),
+        ]);
+        let sdta = list(b"sdta", &[chunk(b"smpl", &smpl)]);
+        chunk(b"RIFF", &[b"sfbk".to_vec(), sdta, pdta].concat())
+    }
+
+    #[test]
+    fn finds_presets() {
+        let sf = SoundFont::parse(&font()).unwrap();
+        assert!(sf.has_preset(0, 5));
+        assert!(!sf.has_preset(0, 6));
+        // Other banks fall back to bank 0.
+        assert!(sf.has_preset(8, 5));
+    }
+
+    #[test]
+    fn merges_zones() {
+        let sf = SoundFont::parse(&font()).unwrap();
+        // Outside of the global preset zone's keys.
+        assert!(sf.syn(0, 5, 59, 100).is_none());
+        assert!(sf.syn(0, 5, 73, 100).is_none());
+
+        let mut out = vec![[0.0; 2]; 4410];
+        let mut one_shot = sf.syn(0, 5, 60, 100).unwrap();
+        assert_eq!(one_shot.0.len(), 1);
+        assert!(!one_shot.render(&mut out));
+        assert!(out.iter().any(|f| f[0].abs() > 0.1));
+
+        let mut looped = sf.syn(0, 5, 72, 100).unwrap();
+        for _ in 0..10 {
+            assert!(looped.render(&mut out));
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(SoundFont::parse(&chunk(b"RIFF", b"WAVE")).is_err());
+    }
+}
@@ -0,0 +1,447 @@
+use crate::types::{R, Frame, SAMPLE_RATE};
+use crate::sample_reader::{load_pcm, SampleLoop};
+use crate::soundprim::pan_gains;
+use super::sampler::{SampleVoice, Span, LoopMode, Dahdsr};
+use super::voice::{Voice, Layered};
+use super::instrument::Instrument;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+type Opcodes = HashMap<String, String>;
+
+enum Token {
+    Header(String),
+    Opcode(String, String),
+}
+
+// Values run up to the next opcode or header, so that sample paths may
+// contain spaces.
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    for line in text.lines() {
+        let line = line.split("//").next().unwrap();
+        if line.trim_start().starts_with('#') {
+            // #define and #include are not supported.
+            continue;
+        }
+        let line = line.replace('<', " <").replace('>', "> ");
+        for word in line.split_whitespace() {
+            if word.starts_with('<') && word.ends_with('>') {
+                tokens.push(Token::Header(word[1..word.len() - 1].to_string()));
+            } else if let Some((k, v)) = word.split_once('=') {
+                tokens.push(Token::Opcode(k.to_string(), v.to_string()));
+            } else if let Some(Token::Opcode(_, v)) = tokens.last_mut() {
+                v.push(' ');
+                v.push_str(word);
+            }
+        }
+    }
+    tokens
+}
+
+// MIDI key of a number or a note name like c#4 or eb3. C4 is 60.
+fn parse_key(s: &str) -> Option<i32> {
+    if let Ok(k) = s.parse() {
+        return Some(k);
+    }
+    let s = s.to_lowercase();
+    let mut chars = s.chars();
+    let mut key = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let octave = if let Some(o) = rest.strip_prefix('#') {
+        key += 1;
+        o
+    } else if let Some(o) = rest.strip_prefix('b') {
+        key -= 1;
+        o
+    } else {
+        rest
+    };
+    Some(key + (octave.parse::<i32>().ok()? + 1) * 12)
+}
+
+fn sustain_to_db(percent: f64) -> f64 {
+    if percent <= 0.0 {
+        -100.0
+    } else {
+        20.0 * (percent.min(100.0) / 100.0).log10()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Trigger {
+    Attack,
+    Release,
+}
+
+struct Region {
+    // Into Sfz::samples
+    sample: usize,
+    keys: (i32, i32),
+    vels: (i32, i32),
+    pitch_keycenter: i32,
+    // Cents per key.
+    pitch_keytrack: f64,
+    // Cents, transpose included.
+    tune: f64,
+    // dB
+    volume: f64,
+    // -100 (left) to 100 (right)
+    pan: f64,
+    // Percent
+    amp_veltrack: f64,
+    offset: usize,
+    end: Option<usize>,
+    loop_mode: LoopMode,
+    // [start, end)
+    loop_points: Option<(usize, usize)>,
+    ping_pong: bool,
+    // Seconds
+    loop_crossfade: f64,
+    trigger: Trigger,
+    env: Dahdsr,
+}
+
+impl Region {
+    // Loop points and type not given by the opcodes come from the
+    // sample file.
+    fn new(ops: &Opcodes, sample: usize, sample_loop: Option<SampleLoop>) -> Self {
+        let get = |names: &[&str]| names.iter().find_map(|n| ops.get(*n));
+        let num = |names: &[&str], default: f64| {
+            get(names).and_then(|v| v.parse().ok()).unwrap_or(default)
+        };
+        let key = |name: &str| get(&[name]).and_then(|v| parse_key(v));
+
+        let loop_end = get(&["loop_end", "loopend"]).and_then(|v| v.parse::<usize>().ok());
+        let loop_points = match (loop_end, sample_loop) {
+            // loop_end is the last sample of the loop.
+            (Some(end), _) => Some((num(&["loop_start", "loopstart"], 0.0) as usize, end + 1)),
+            (None, Some(l)) => Some((l.start, l.end)),
+            (None, None) => None,
+        };
+        let loop_mode = match get(&["loop_mode", "loopmode"]).map(String::as_str) {
+            Some("one_shot") => LoopMode::OneShot,
+            Some("loop_continuous") => LoopMode::Continuous,
+            Some("loop_sustain") => LoopMode::UntilRelease,
+            Some(_) => LoopMode::NoLoop,
+            // Loop if there is something to loop.
+            None if loop_points.is_some() => LoopMode::Continuous,
+            None => LoopMode::NoLoop,
+        };
+        let ping_pong = match get(&["loop_type"]).map(String::as_str) {
+            Some(t) => t == "alternate",
+            None => sample_loop.is_some_and(|l| l.alternate),
+        };
+        let trigger = match get(&["trigger"]).map(String::as_str) {
+            Some("release") => Trigger::Release,
+            _ => Trigger::Attack,
+        };
+
+        Region {
+            sample,
+            keys: (key("lokey").or(key("key")).unwrap_or(0),
+                   key("hikey").or(key("key")).unwrap_or(127)),
+            vels: (num(&["lovel"], 0.0) as i32, num(&["hivel"], 127.0) as i32),
+            pitch_keycenter: key("pitch_keycenter").or(key("key")).unwrap_or(60),
+            pitch_keytrack: num(&["pitch_keytrack"], 100.0),
+            tune: num(&["tune"], 0.0) + num(&["transpose"], 0.0) * 100.0,
+            volume: num(&["volume"], 0.0),
+            pan: num(&["pan"], 0.0),
+            amp_veltrack: num(&["amp_veltrack"], 100.0),
+            offset: num(&["offset"], 0.0) as usize,
+            end: get(&["end"]).and_then(|v| v.parse().ok()),
+            loop_mode,
+            loop_points,
+            ping_pong,
+            loop_crossfade: num(&["loop_crossfade"], 0.0),
+            trigger,
+            env: Dahdsr {
+                delay: num(&["ampeg_delay"], 0.0),
+                attack: num(&["ampeg_attack"], 0.0),
+                hold: num(&["ampeg_hold"], 0.0),
+                decay: num(&["ampeg_decay"], 0.0),
+                sustain: sustain_to_db(num(&["ampeg_sustain"], 100.0)),
+                release: num(&["ampeg_release"], 0.001),
+            },
+        }
+    }
+
+    fn matches(&self, key: u8, velo: u8) -> bool {
+        (self.keys.0..=self.keys.1).contains(&(key as i32))
+            && (self.vels.0..=self.vels.1).contains(&(velo as i32))
+    }
+}
+
+struct Sample {
+    channels: Vec<Arc<Vec<f32>>>,
+    sample_rate: f64,
+    sample_loop: Option<SampleLoop>,
+}
+
+// An SFZ instrument: regions of WAV or FLAC samples.
+pub struct Sfz {
+    samples: Vec<Sample>,
+    regions: Vec<Region>,
+}
+
+impl Sfz {
+    pub fn load(path: &str) -> R<Self> {
+        let text = fs::read_to_string(path)?;
+        let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+        Self::parse(&text, dir)
+    }
+
+    // Sample paths are relative to dir.
+    pub fn parse(text: &str, dir: &Path) -> R<Self> {
+        let mut sfz = Sfz { samples: vec![], regions: vec![] };
+        let mut loaded: HashMap<PathBuf, usize> = HashMap::new();
+
+        // Opcodes are inherited from the enclosing headers.
+        let mut control = Opcodes::new();
+        let mut global = Opcodes::new();
+        let mut master = Opcodes::new();
+        let mut group = Opcodes::new();
+        let mut region: Option<Opcodes> = None;
+        let mut header = String::new();
+
+        let tokens = tokenize(text);
+        // A trailing header closes the last region.
+        let end = Token::Header(String::new());
+        for token in tokens.iter().chain(Some(&end)) {
+            let (k, v) = match token {
+                Token::Opcode(k, v) => (k.clone(), v.clone()),
+                Token::Header(h) => {
+                    if let Some(ops) = region.take() {
+                        sfz.add_region(ops, &control, dir, &mut loaded)?;
+                    }
+                    match h.as_str() {
+                        "global" => {
+                            global.clear();
+                            master.clear();
+                            group.clear();
+                        }
+                        "master" => {
+                            master.clear();
+                            group.clear();
+                        }
+                        "group" => group.clear(),
+                        "region" => {
+                            let mut ops = global.clone();
+                            ops.extend(master.clone());
+                            ops.extend(group.clone());
+                            region = Some(ops);
+                        }
+                        _ => {},
+                    }
+                    header = h.clone();
+                    continue;
+                }
+            };
+            let ops = match header.as_str() {
+                "control" => &mut control,
+                "global" => &mut global,
+                "master" => &mut master,
+                "group" => &mut group,
+                "region" => region.as_mut().unwrap(),
+                _ => continue,
+            };
+            ops.insert(k, v);
+        }
+        Ok(sfz)
+    }
+
+    fn add_region(&mut self, ops: Opcodes, control: &Opcodes, dir: &Path,
+                  loaded: &mut HashMap<PathBuf, usize>) -> R<()> {
+        let name = match ops.get("sample") {
+            // Names starting with * are built in generators, not files.
+            Some(name) if !name.starts_with('*') => name.replace('\\', "/"),
+            _ => return Ok(()),
+        };
+        let default_path = control.get("default_path").map_or("", String::as_str);
+        let path = dir.join(default_path.replace('\\', "/")).join(name);
+
+        let sample = match loaded.get(&path) {
+            Some(&ix) => ix,
+            None => {
+                let pcm = load_pcm(&path.to_string_lossy())?;
+                self.samples.push(Sample {
+                    channels: pcm.channels.into_iter().map(Arc::new).collect(),
+                    sample_rate: pcm.sample_rate,
+                    sample_loop: pcm.sample_loop,
+                });
+                loaded.insert(path, self.samples.len() - 1);
+                self.samples.len() - 1
+            }
+        };
+        self.regions.push(Region::new(&ops, sample, self.samples[sample].sample_loop));
+        Ok(())
+    }
+
+    pub fn syn(&self, key: u8, velo: u8) -> Option<SfzVoice> {
+        let mut held = vec![];
+        let mut on_release = vec![];
+        for r in self.regions.iter().filter(|r| r.matches(key, velo)) {
+            match r.trigger {
+                Trigger::Attack => held.extend(self.voices(r, key, velo)),
+                Trigger::Release => on_release.extend(self.voices(r, key, velo)),
+            }
+        }
+        if held.is_empty() && on_release.is_empty() {
+            None
+        } else {
+            Some(SfzVoice { held: Layered(held), on_release, released: false })
+        }
+    }
+
+    // One voice per channel of the region's sample.
+    fn voices(&self, r: &Region, key: u8, velo: u8) -> Vec<SampleVoice> {
+        let sample = &self.samples[r.sample];
+        let len = sample.channels[0].len();
+        let (loop_start, loop_end) = r.loop_points.unwrap_or((0, 0));
+        let span = Span {
+            start: r.offset,
+            end: r.end.map_or(len, |e| e + 1),
+            loop_start,
+            loop_end,
+            // Release samples play out by themselves.
+            mode: if r.trigger == Trigger::Release { LoopMode::OneShot } else { r.loop_mode },
+            ping_pong: r.ping_pong,
+            crossfade: (r.loop_crossfade * sample.sample_rate) as usize,
+        };
+
+        let cents = (key as i32 - r.pitch_keycenter) as f64 * r.pitch_keytrack + r.tune;
+        let step = 2f64.powf(cents / 1200.0) * sample.sample_rate / SAMPLE_RATE;
+
+        let velo_gain = (velo as f64 / 127.0).powi(2);
+        let gain = 10f64.powf(r.volume / 20.0)
+            * (1.0 - r.amp_veltrack / 100.0 * (1.0 - velo_gain));
+        let (l, rt) = pan_gains(((r.pan + 100.0) / 200.0) as f32);
+        let (l, rt) = (l * gain as f32, rt * gain as f32);
+
+        // Stereo samples have their channels panned hard.
+        let gains: Vec<Frame> = match sample.channels.len() {
+            1 => vec![[l, rt]],
+            _ => vec![[l, 0.0], [0.0, rt]],
+        };
+        sample.channels.iter().zip(gains).map(|(data, [gl, gr])| {
+            SampleVoice::new(data.clone(), span, step, (gl, gr), r.env)
+        }).collect()
+    }
+}
+
+impl Instrument for Sfz {
+    fn note_on(&mut self, _ch: u8, key: u8, velo: u8) -> Option<Box<dyn Voice>> {
+        let v = self.syn(key, velo)?;
+        Some(Box::new(v))
+    }
+}
+
+// The regions triggered by a key. Release triggered regions start sounding
+// when the key is released.
+pub struct SfzVoice {
+    held: Layered<SampleVoice>,
+    on_release: Vec<SampleVoice>,
+    released: bool,
+}
+
+impl Voice for SfzVoice {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        let held = self.held.render(out);
+        if self.released {
+            self.on_release.retain_mut(|v| v.render(out));
+        }
+        held || !self.on_release.is_empty()
+    }
+
+    fn set_pitch_bend(&mut self, ratio: f64) {
+        self.held.set_pitch_bend(ratio);
+        for v in &mut self.on_release {
+            v.set_pitch_bend(ratio);
+        }
+    }
+
+    fn release(&mut self) {
+        self.held.release();
+        self.released = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::save_wav;
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(parse_key("60"), Some(60));
+        assert_eq!(parse_key("c4"), Some(60));
+        assert_eq!(parse_key("C#4"), Some(61));
+        assert_eq!(parse_key("eb3"), Some(51));
+        assert_eq!(parse_key("a-1"), Some(9));
+        assert_eq!(parse_key("h2"), None);
+    }
+
+    #[test]
+    fn values_run_to_next_opcode() {
+        let tokens = tokenize("<region>sample=My Piano/c 4.wav lokey=1 // hikey=2\n#define $X 1");
+        let ops: Vec<_> = tokens.iter().filter_map(|t| match t {
+            Token::Opcode(k, v) => Some((k.as_str(), v.as_str())),
+            Token::Header(_) => None,
+        }).collect();
+        assert_eq!(ops, [("sample", "My Piano/c 4.wav"), ("lokey", "1")]);
+    }
+
+    #[test]
+    fn inherits_opcodes() {
+        let dir = std::env::temp_dir()
+            .join(format!("music_syn_sfz_inherits_opcodes_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let wav = dir.join("a b.wav");
+        save_wav((0..1000).map(|i| (i as f32 * 0.1).sin() * 0.5), wav.to_str().unwrap(), 1).unwrap();
+
+        let sfz = Sfz::parse("
+            <global> volume=-6 ampeg_release=0.5
+            <group> lokey=c4 hikey=b4 pitch_keycenter=c4
+            <region> sample=a b.wav loop_start=10 loop_end=99
+            <region> sample=a b.wav lokey=c5 hikey=c5 trigger=release
+            <group> key=40
+            <region> sample=*sine
+        ", &dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Loaded once, and built in generators are skipped.
+        assert_eq!(sfz.samples.len(), 1);
+        assert_eq!(sfz.regions.len(), 2);
+        let r = &sfz.regions[0];
+        assert_eq!((r.keys, r.pitch_keycenter, r.volume), ((60, 71), 60, -6.0));
+        assert_eq!(r.env.release, 0.5);
+        assert_eq!(r.loop_points, Some((10, 100)));
+        assert!(matches!(r.loop_mode, LoopMode::Continuous));
+        let r = &sfz.regions[1];
+        assert_eq!(r.keys, (72, 72));
+        assert!(r.trigger == Trigger::Release && r.loop_points.is_none());
+
+        assert!(sfz.syn(60, 100).is_some_and(|v| v.held.0.len() == 1));
+        // Only sounds once released.
+        let mut v = sfz.syn(72, 100).unwrap();
+        let mut out = vec![[0.0; 2]; 100];
+        v.render(&mut out);
+        assert!(out.iter().all(|f| f[0] == 0.0));
+        v.release();
+        v.render(&mut out);
+        assert!(out.iter().any(|f| f[0] != 0.0));
+        assert!(sfz.syn(40, 100).is_none());
+        assert!(sfz.syn(80, 100).is_none());
+    }
+}
@@ -1,8 +1,8 @@
-use crate::types::Sound;
-use crate::soundprim::{
-    Envelope,
-    sinewave,
-};
+use std::f64::consts::PI;
+use crate::types::Frame;
+use crate::soundprim::{Adsr, AdsrEnv, Curve};
+use super::voice::Voice;
+use super::instrument::Instrument;
 
 fn freq_wrt_c4(key: i32) -> f64 {
     let half_step = 1.0595_f64;
@@ -12,31 +12,75 @@ fn freq_wrt_c4(key: i32) -> f64 {
 pub struct Sine {
     // Number of semitones wrt C4.
     pub key: i32,
-    pub duration: f64,
     pub amp: f64,
     pub sample_rate: f64,
 }
 
 impl Sine {
-    pub fn syn(&self) -> impl Sound {
-        let mut env = Envelope::default();
+    pub fn syn(&self) -> SineVoice {
         // Looks more like piano.
-        env.attack = 0.03;
-        env.decay = 0.04;
-        env.sustain = 0.2;
-        env.release = 0.73;
-
-        env.attack_plier = 2.0;
-        env.decay_plier = 1.0;
-        env.sustain_plier = 0.7;
-
-        env.amp = self.amp;
-        let ss = sinewave(
-            freq_wrt_c4(self.key),
-            (self.sample_rate * self.duration) as usize,
-            self.sample_rate);
-        env.mult(ss, self.duration)
+        let env = Adsr {
+            attack: 0.01,
+            decay: 1.5,
+            sustain: 0.2,
+            release: 0.3,
+            curve: Curve::Exponential,
+        };
+        SineVoice {
+            phase: 0.0,
+            step: freq_wrt_c4(self.key) / self.sample_rate * 2.0 * PI,
+            ratio: 1.0,
+            amp: self.amp as f32,
+            env: env.make(self.sample_rate),
+        }
     }
+}
+
+// Plays every note as a Sine.
+pub struct SineInstrument {
+    pub sample_rate: f64,
+}
+
+impl Instrument for SineInstrument {
+    fn note_on(&mut self, _ch: u8, key: u8, velo: u8) -> Option<Box<dyn Voice>> {
+        let synthesizer = Sine {
+            key: key as i32 - 60,
+            amp: velo as f64 / 128.0,
+            sample_rate: self.sample_rate,
+        };
+        Some(Box::new(synthesizer.syn()))
+    }
+}
 
+pub struct SineVoice {
+    phase: f64,
+    // Phase increment per sample when unbent.
+    step: f64,
+    ratio: f64,
+    amp: f32,
+    env: AdsrEnv,
 }
 
+impl Voice for SineVoice {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        for o in out.iter_mut() {
+            let env = match self.env.next() {
+                Some(env) => env,
+                None => return false,
+            };
+            let v = self.phase.sin() as f32 * env * self.amp;
+            o[0] += v;
+            o[1] += v;
+            self.phase = (self.phase + self.step * self.ratio) % (2.0 * PI);
+        }
+        true
+    }
+
+    fn set_pitch_bend(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    fn release(&mut self) {
+        self.env.release();
+    }
+}
@@ -0,0 +1,427 @@
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use crate::types::{Frame, R};
+use crate::oscillator::{Oscillator, Waveform, Noise};
+use crate::filter::{Svf, FilterMode};
+use crate::soundprim::{Adsr, AdsrEnv, Curve};
+use super::voice::Voice;
+use super::instrument::Instrument;
+
+// Patch of the subtractive synth. Presets are written as `name = value`
+// lines, # starting a comment, and only need to give what differs from
+// SynthPreset::default():
+//
+//     osc1 = saw
+//     osc2 = saw
+//     osc2_detune = 8
+//     cutoff = 800
+//     filter_env_amount = 2.5
+//     amp_release = 0.4
+#[derive(Clone, Debug)]
+pub struct SynthPreset {
+    pub osc1: Waveform,
+    pub osc2: Waveform,
+    // Pitch of osc2 over osc1, in semitones and cents.
+    pub osc2_semitones: f64,
+    pub osc2_detune: f64,
+    // 0 is only osc1, 1 only osc2.
+    pub osc_mix: f64,
+    // Of both oscillators when playing pulses.
+    pub pulse_width: f64,
+    pub noise: f64,
+    pub filter: FilterMode,
+    // Hz, at C4.
+    pub cutoff: f64,
+    pub resonance: f64,
+    // How much the cutoff follows the key, 1 being fully.
+    pub key_track: f64,
+    pub filter_env: Adsr,
+    // Octaves added to the cutoff at the envelope's peak.
+    pub filter_env_amount: f64,
+    pub amp_env: Adsr,
+    pub lfo_wave: Waveform,
+    // Hz
+    pub lfo_rate: f64,
+    // LFO depth in semitones, octaves of cutoff and fraction of amplitude.
+    pub lfo_pitch: f64,
+    pub lfo_filter: f64,
+    pub lfo_amp: f64,
+    // Octaves of cutoff added at full velocity.
+    pub velo_filter: f64,
+    pub gain: f64,
+}
+
+impl SynthPreset {
+    pub fn default() -> Self {
+        Self {
+            osc1: Waveform::Saw,
+            osc2: Waveform::Saw,
+            osc2_semitones: 0.0,
+            osc2_detune: 0.0,
+            osc_mix: 0.5,
+            pulse_width: 0.5,
+            noise: 0.0,
+            filter: FilterMode::LowPass,
+            cutoff: 2000.0,
+            resonance: 0.2,
+            key_track: 0.5,
+            filter_env: Adsr {
+                attack: 0.01,
+                decay: 0.3,
+                sustain: 0.0,
+                release: 0.3,
+                curve: Curve::Exponential,
+            },
+            filter_env_amount: 0.0,
+            amp_env: Adsr {
+                attack: 0.01,
+                decay: 0.3,
+                sustain: 0.7,
+                release: 0.3,
+                curve: Curve::Exponential,
+            },
+            lfo_wave: Waveform::Sine,
+            lfo_rate: 5.0,
+            lfo_pitch: 0.0,
+            lfo_filter: 0.0,
+            lfo_amp: 0.0,
+            velo_filter: 1.0,
+            gain: 0.4,
+        }
+    }
+
+    pub fn parse(text: &str) -> R<Self> {
+        let mut p = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, value) = line.split_once('=')
+                .ok_or_else(|| format!("line {}: expected name = value", n + 1))?;
+            p.set(name.trim(), value.trim())
+                .map_err(|e| format!("line {}: {}", n + 1, e))?;
+        }
+        Ok(p)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> R<()> {
+        let num = || value.parse::<f64>()
+            .map_err(|_| format!("{} is not a number", value));
+        match name {
+            "osc1" => self.osc1 = parse_waveform(value)?,
+            "osc2" => self.osc2 = parse_waveform(value)?,
+            "osc2_semitones" => self.osc2_semitones = num()?,
+            "osc2_detune" => self.osc2_detune = num()?,
+            "osc_mix" => self.osc_mix = num()?,
+            "pulse_width" => self.pulse_width = num()?,
+            "noise" => self.noise = num()?,
+            "filter" => self.filter = match value {
+                "lowpass" => FilterMode::LowPass,
+                "highpass" => FilterMode::HighPass,
+                "bandpass" => FilterMode::BandPass,
+                "notch" => FilterMode::Notch,
+                _ => return Err(format!("unknown filter {}", value).into()),
+            },
+            "cutoff" => self.cutoff = num()?,
+            "resonance" => self.resonance = num()?,
+            "key_track" => self.key_track = num()?,
+            "filter_env_amount" => self.filter_env_amount = num()?,
+            "filter_attack" => self.filter_env.attack = num()?,
+            "filter_decay" => self.filter_env.decay = num()?,
+            "filter_sustain" => self.filter_env.sustain = num()?,
+            "filter_release" => self.filter_env.release = num()?,
+            "amp_attack" => self.amp_env.attack = num()?,
+            "amp_decay" => self.amp_env.decay = num()?,
+            "amp_sustain" => self.amp_env.sustain = num()?,
+            "amp_release" => self.amp_env.release = num()?,
+            "lfo" => self.lfo_wave = parse_waveform(value)?,
+            "lfo_rate" => self.lfo_rate = num()?,
+            "lfo_pitch" => self.lfo_pitch = num()?,
+            "lfo_filter" => self.lfo_filter = num()?,
+            "lfo_amp" => self.lfo_amp = num()?,
+            "velo_filter" => self.velo_filter = num()?,
+            "gain" => self.gain = num()?,
+            _ => return Err(format!("unknown parameter {}", name).into()),
+        }
+        Ok(())
+    }
+}
+
+fn parse_waveform(s: &str) -> R<Waveform> {
+    Ok(match s {
+        "sine" => Waveform::Sine,
+        "saw" => Waveform::Saw,
+        "square" => Waveform::Square,
+        "triangle" => Waveform::Triangle,
+        "pulse" => Waveform::Pulse,
+        _ => return Err(format!("unknown waveform {}", s).into()),
+    })
+}
+
+const BASS: &str = "
+osc1 = saw
+osc2 = square
+osc2_semitones = -12
+osc_mix = 0.4
+cutoff = 300
+resonance = 0.35
+key_track = 0.3
+filter_env_amount = 2.5
+filter_decay = 0.25
+amp_attack = 0.002
+amp_decay = 0.6
+amp_sustain = 0.6
+amp_release = 0.08
+gain = 0.5
+";
+
+const STRINGS: &str = "
+osc1 = saw
+osc2 = saw
+osc2_detune = 9
+cutoff = 2200
+resonance = 0.1
+amp_attack = 0.25
+amp_decay = 1
+amp_sustain = 0.9
+amp_release = 0.5
+lfo_rate = 5.5
+lfo_pitch = 0.08
+gain = 0.3
+";
+
+const LEAD: &str = "
+osc1 = saw
+osc2 = pulse
+pulse_width = 0.3
+osc2_detune = 6
+cutoff = 1500
+resonance = 0.4
+filter_env_amount = 1.5
+filter_decay = 0.4
+amp_attack = 0.005
+amp_sustain = 0.8
+amp_release = 0.15
+lfo_rate = 5.5
+lfo_pitch = 0.15
+gain = 0.35
+";
+
+const PAD: &str = "
+osc1 = saw
+osc2 = triangle
+osc2_semitones = 12
+osc2_detune = 5
+noise = 0.03
+cutoff = 900
+resonance = 0.25
+filter_env_amount = 1
+filter_attack = 1.5
+filter_decay = 2
+filter_sustain = 0.5
+filter_release = 1.5
+amp_attack = 0.8
+amp_decay = 2
+amp_sustain = 0.8
+amp_release = 1.5
+lfo = triangle
+lfo_rate = 0.3
+lfo_filter = 0.5
+lfo_amp = 0.1
+gain = 0.3
+";
+
+// GM programs that the built-in presets stand in for.
+pub fn gm_presets() -> Vec<(RangeInclusive<u8>, SynthPreset)> {
+    let presets = [
+        (32..=39, BASS),
+        (40..=46, STRINGS),
+        // Not timpani (47), which is struck.
+        (48..=51, STRINGS),
+        (52..=55, PAD),
+        (80..=87, LEAD),
+        (88..=95, PAD),
+    ];
+    presets.into_iter().map(|(programs, text)| {
+        (programs, SynthPreset::parse(text).expect("built-in preset"))
+    }).collect()
+}
+
+// Polyphonic subtractive synth: two oscillators and noise through a
+// resonant filter, with envelopes on the cutoff and amplitude and an LFO on
+// pitch, cutoff and amplitude.
+pub struct Synth {
+    pub preset: Arc<SynthPreset>,
+    pub sample_rate: f64,
+    // Seeds the noise of each voice differently.
+    seed: u32,
+}
+
+impl Synth {
+    pub fn new(preset: SynthPreset, sample_rate: f64) -> Self {
+        Self {
+            preset: Arc::new(preset),
+            sample_rate,
+            seed: 1,
+        }
+    }
+
+    pub fn syn(&mut self, key: u8, velo: u8) -> SynthVoice {
+        let p = self.preset.clone();
+        let sr = self.sample_rate;
+        let freq = 440.0 * 2f64.powf((key as f64 - 69.0) / 12.0);
+        let velo = velo as f64 / 127.0;
+        let cutoff = p.cutoff
+            * 2f64.powf((key as f64 - 60.0) / 12.0 * p.key_track)
+            * 2f64.powf(p.velo_filter * (velo - 1.0));
+        self.seed = self.seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+
+        let mut osc1 = Oscillator::new(p.osc1, freq, sr);
+        let mut osc2 = Oscillator::new(p.osc2, freq, sr)
+            .with_phase(self.seed as f64 / u32::MAX as f64);
+        osc1.width = p.pulse_width;
+        osc2.width = p.pulse_width;
+        SynthVoice {
+            osc1,
+            osc2,
+            osc2_ratio: 2f64.powf((p.osc2_semitones + p.osc2_detune / 100.0) / 12.0),
+            noise: Noise::new(self.seed),
+            lfo: Oscillator::new(p.lfo_wave, p.lfo_rate, sr),
+            filter: Svf::new(p.filter, cutoff, p.resonance, sr),
+            filter_env: p.filter_env.make(sr),
+            amp_env: p.amp_env.make(sr),
+            freq,
+            cutoff,
+            ratio: 1.0,
+            amp: (velo * p.gain) as f32,
+            preset: p,
+        }
+    }
+}
+
+impl Instrument for Synth {
+    fn note_on(&mut self, _ch: u8, key: u8, velo: u8) -> Option<Box<dyn Voice>> {
+        Some(Box::new(self.syn(key, velo)))
+    }
+}
+
+pub struct SynthVoice {
+    osc1: Oscillator,
+    osc2: Oscillator,
+    osc2_ratio: f64,
+    noise: Noise,
+    lfo: Oscillator,
+    filter: Svf,
+    filter_env: AdsrEnv,
+    amp_env: AdsrEnv,
+    // Hz, unbent.
+    freq: f64,
+    cutoff: f64,
+    ratio: f64,
+    amp: f32,
+    preset: Arc<SynthPreset>,
+}
+
+impl Voice for SynthVoice {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        let p = &*self.preset;
+        for o in out.iter_mut() {
+            let env = match self.amp_env.next() {
+                Some(env) => env,
+                None => return false,
+            };
+            let fenv = self.filter_env.next().unwrap_or(0.0) as f64;
+            let lfo = self.lfo.next().unwrap_or(0.0) as f64;
+
+            let freq = self.freq * self.ratio * 2f64.powf(lfo * p.lfo_pitch / 12.0);
+            let v1 = self.osc1.tick(freq);
+            let v2 = self.osc2.tick(freq * self.osc2_ratio);
+            let n = self.noise.next().unwrap_or(0.0);
+            let mix = v1 * (1.0 - p.osc_mix) as f32 + v2 * p.osc_mix as f32 + n * p.noise as f32;
+
+            let cutoff = self.cutoff * 2f64.powf(fenv * p.filter_env_amount + lfo * p.lfo_filter);
+            self.filter.set(cutoff, p.resonance);
+            // The LFO only ever takes amplitude away.
+            let tremolo = 1.0 - p.lfo_amp * 0.5 * (1.0 - lfo);
+            let v = self.filter.process(mix) * env * self.amp * tremolo as f32;
+            o[0] += v;
+            o[1] += v;
+        }
+        true
+    }
+
+    fn set_pitch_bend(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    fn release(&mut self) {
+        self.amp_env.release();
+        self.filter_env.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        SynthPreset::parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn overrides_defaults() {
+        let p = SynthPreset::parse("
+            # A bass
+            osc2 = square  # an octave down
+            osc2_semitones = -12
+            filter = bandpass
+            filter_env_amount = 2.5
+            filter_decay = 0.25
+        ").unwrap();
+        assert_eq!(p.osc2, Waveform::Square);
+        assert_eq!(p.osc2_semitones, -12.0);
+        assert_eq!(p.filter, FilterMode::BandPass);
+        assert_eq!(p.filter_env_amount, 2.5);
+        assert_eq!(p.filter_env.decay, 0.25);
+        // The rest is left alone.
+        let d = SynthPreset::default();
+        assert_eq!(p.osc1, d.osc1);
+        assert_eq!(p.cutoff, d.cutoff);
+        assert_eq!(p.filter_env.attack, d.filter_env.attack);
+    }
+
+    #[test]
+    fn rejects_unknown_parameters() {
+        assert_eq!(error("cutoff = 800\nfilter_env = 2"), "line 2: unknown parameter filter_env");
+    }
+
+    #[test]
+    fn rejects_non_numbers() {
+        assert_eq!(error("\n\ncutoff = high"), "line 3: high is not a number");
+    }
+
+    #[test]
+    fn rejects_unknown_waveforms() {
+        assert_eq!(error("osc1 = saw\nlfo = wobble"), "line 2: unknown waveform wobble");
+    }
+
+    #[test]
+    fn rejects_unknown_filters() {
+        assert_eq!(error("filter = comb"), "line 1: unknown filter comb");
+    }
+
+    #[test]
+    fn rejects_lines_without_values() {
+        assert_eq!(error("# osc1 = saw\ncutoff"), "line 2: expected name = value");
+    }
+
+    #[test]
+    fn builds_gm_presets() {
+        let presets = gm_presets();
+        assert!(!presets.is_empty());
+        assert!(presets.iter().all(|(programs, _)| !programs.contains(&47)));
+        let (_, bass) = presets.iter().find(|(programs, _)| programs.contains(&32)).unwrap();
+        assert_eq!(bass.filter_env_amount, 2.5);
+    }
+}
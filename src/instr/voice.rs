@@ -0,0 +1,137 @@
+use crate::types::{Frame, SAMPLE_RATE};
+use crate::soundprim::{Adsr, AdsrEnv, Curve};
+
+// A sounding note that can still be altered while it plays.
+pub trait Voice: Send {
+    // Mixes the next out.len() frames into out. Returns false once the
+    // voice has died out.
+    fn render(&mut self, out: &mut [Frame]) -> bool;
+
+    // Frequency multiplier from the channel's pitch bend, 1.0 is unbent.
+    fn set_pitch_bend(&mut self, ratio: f64);
+
+    // The key is released.
+    fn release(&mut self);
+
+    // Cut off by another key of its choke group, which also stops one-shots
+    // that play on after release.
+    fn choke(&mut self) {
+        self.release();
+    }
+}
+
+impl<V: Voice + ?Sized> Voice for Box<V> {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        (**self).render(out)
+    }
+
+    fn set_pitch_bend(&mut self, ratio: f64) {
+        (**self).set_pitch_bend(ratio);
+    }
+
+    fn release(&mut self) {
+        (**self).release();
+    }
+
+    fn choke(&mut self) {
+        (**self).choke();
+    }
+}
+
+// The short fade out applied to released notes. Yields 1.0 until released.
+pub struct Releaser(AdsrEnv);
+
+impl Releaser {
+    pub fn new() -> Self {
+        let adsr = Adsr {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.1,
+            curve: Curve::Linear,
+        };
+        Releaser(adsr.make(SAMPLE_RATE))
+    }
+
+    pub fn release(&mut self) {
+        self.0.release();
+    }
+}
+
+impl Default for Releaser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iterator for Releaser {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.0.next()
+    }
+}
+
+// Several voices sounding as one note.
+pub struct Layered<V>(pub Vec<V>);
+
+impl<V: Voice> Voice for Layered<V> {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        self.0.retain_mut(|v| v.render(out));
+        !self.0.is_empty()
+    }
+
+    fn set_pitch_bend(&mut self, ratio: f64) {
+        for v in &mut self.0 {
+            v.set_pitch_bend(ratio);
+        }
+    }
+
+    fn release(&mut self) {
+        for v in &mut self.0 {
+            v.release();
+        }
+    }
+
+    fn choke(&mut self) {
+        for v in &mut self.0 {
+            v.choke();
+        }
+    }
+}
+
+// A steady level until released, or as a string, which the key leaves
+// ringing, until damped. For testing what plays a voice.
+#[cfg(test)]
+pub(crate) struct Tone {
+    level: f32,
+    string: bool,
+    released: bool,
+}
+
+#[cfg(test)]
+impl Tone {
+    pub(crate) fn new(level: f32, string: bool) -> Self {
+        Self { level, string, released: false }
+    }
+}
+
+#[cfg(test)]
+impl Voice for Tone {
+    fn render(&mut self, out: &mut [Frame]) -> bool {
+        if self.released {
+            return false;
+        }
+        for o in out.iter_mut() {
+            o[0] += self.level;
+            o[1] += self.level;
+        }
+        true
+    }
+
+    fn set_pitch_bend(&mut self, _ratio: f64) {}
+
+    fn release(&mut self) {
+        self.released = !self.string;
+    }
+}
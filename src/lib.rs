@@ -1,11 +1,13 @@
-#![feature(trait_alias)]
-#![feature(generators, generator_trait)]
-
 pub mod playback;
 pub mod types;
 pub mod sample_reader;
 pub mod soundprim;
+pub mod oscillator;
+pub mod filter;
+pub mod loopfind;
+pub mod damper;
 pub mod midisyn;
+pub mod sequencer;
+pub mod transport;
 pub mod instr;
 pub mod writer;
-pub mod geniter;
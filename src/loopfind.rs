@@ -0,0 +1,180 @@
+use crate::sample_reader::SampleLoop;
+
+// Samples compared around the loop points.
+const MATCH_LEN: usize = 512;
+
+// RMS of consecutive windows of len samples.
+fn rms_envelope(ss: &[f32], len: usize) -> Vec<f32> {
+    ss.chunks(len).map(|w| {
+        (w.iter().map(|x| x * x).sum::<f32>() / w.len() as f32).sqrt()
+    }).collect()
+}
+
+fn is_rising_zero(ss: &[f32], i: usize) -> bool {
+    i > 0 && ss[i - 1] < 0.0 && ss[i] >= 0.0
+}
+
+// How much the neighbourhoods of a and b differ, relative to their energy.
+// 0 is a perfect match.
+fn mismatch(ss: &[f32], a: usize, b: usize) -> f32 {
+    let half = MATCH_LEN / 2;
+    let wa = &ss[a - half..a + half];
+    let wb = &ss[b - half..b + half];
+    let diff: f32 = wa.iter().zip(wb).map(|(x, y)| (x - y) * (x - y)).sum();
+    let energy: f32 = wa.iter().chain(wb).map(|x| x * x).sum();
+    diff / energy.max(f32::EPSILON)
+}
+
+// Period in samples of the strongest pitch in ss, between min and max, by
+// normalized autocorrelation. The shortest lag that correlates nearly as
+// well as the best one wins, to avoid picking a multiple of the period.
+pub fn find_period(ss: &[f32], min: usize, max: usize) -> Option<f64> {
+    if ss.len() <= max * 2 || min < 1 || min >= max {
+        return None;
+    }
+    let n = ss.len() - max;
+    let corr: Vec<f32> = (0..=max).map(|lag| {
+        if lag < min - 1 {
+            return 0.0;
+        }
+        let (a, b) = (&ss[..n], &ss[lag..lag + n]);
+        let ab: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+        let aa: f32 = a.iter().map(|x| x * x).sum();
+        let bb: f32 = b.iter().map(|x| x * x).sum();
+        ab / (aa * bb).sqrt().max(f32::EPSILON)
+    }).collect();
+
+    let is_peak = |lag: usize| corr[lag] > corr[lag - 1] && corr[lag] >= corr[lag + 1];
+    let best = (min..max).filter(|&l| is_peak(l)).map(|l| corr[l]).reduce(f32::max)?;
+    if best <= 0.0 {
+        return None;
+    }
+    let lag = (min..max).find(|&l| is_peak(l) && corr[l] >= best * 0.9)?;
+
+    // Parabolic interpolation around the peak.
+    let (l, c, r) = (corr[lag - 1], corr[lag], corr[lag + 1]);
+    let denom = l - 2.0 * c + r;
+    let shift = if denom.abs() > f32::EPSILON { 0.5 * (l - r) / denom } else { 0.0 };
+    Some(lag as f64 + shift as f64)
+}
+
+// Finds a loop in the sustain of ss: from 100 ms after the loudest part until
+// the level has fallen by 20 dB. Both ends are rising zero crossings (around
+// the mean) about a whole number of periods apart, picked so that the
+// waveforms around them match best. Longer loops are preferred.
+pub fn find_loop(ss: &[f32], sample_rate: f64) -> Option<SampleLoop> {
+    let win = (sample_rate * 0.01) as usize;
+    if win == 0 {
+        return None;
+    }
+    let env = rms_envelope(ss, win);
+    let (peak_w, &peak) = env.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))?;
+    if peak <= 0.0 {
+        return None;
+    }
+    let first = peak_w * win + (sample_rate * 0.1) as usize + MATCH_LEN / 2;
+    let last_w = env.iter().rposition(|&e| e >= peak * 0.1)?;
+    let last = ((last_w + 1) * win).min(ss.len()).saturating_sub(MATCH_LEN / 2 + 1);
+    if last <= first + MATCH_LEN {
+        return None;
+    }
+    // Recordings may have a DC offset, which would leave few zero crossings.
+    let dc = ss[first..last].iter().sum::<f32>() / (last - first) as f32;
+    let ss: Vec<f32> = ss.iter().map(|x| x - dc).collect();
+    let ss = &ss[..];
+
+    // 30 Hz to 2 kHz, from the middle of the sustain.
+    let (min_lag, max_lag) = ((sample_rate / 2000.0) as usize, (sample_rate / 30.0) as usize);
+    let mid = (first + last) / 2;
+    let analysed = &ss[mid.saturating_sub(max_lag * 2)..(mid + max_lag * 2).min(ss.len())];
+    let period = find_period(analysed, min_lag, max_lag)?;
+
+    let start = (first..last).find(|&i| is_rising_zero(ss, i))?;
+    let periods = ((last - start) as f64 / period) as usize;
+    let mut best: Option<(f32, usize)> = None;
+    for k in (1..=periods).rev().take(8) {
+        // The period estimate is only so exact, so look within a whole
+        // period of where the end should be.
+        let guess = start as f64 + k as f64 * period;
+        let lo = (guess - period / 2.0) as usize;
+        let hi = ((guess + period / 2.0) as usize).min(last);
+        for end in (lo..=hi).filter(|&i| is_rising_zero(ss, i)) {
+            let m = mismatch(ss, start, end);
+            if best.is_none_or(|(bm, _)| m < bm) {
+                best = Some((m, end));
+            }
+        }
+    }
+    let (_, end) = best?;
+    Some(SampleLoop { start, end, alternate: false })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+    use crate::sample_reader::{load_wav, wav_sample_loop};
+    use crate::writer::{save_wav, write_smpl};
+
+    const SAMPLE_RATE: f64 = 44100.0;
+    // 220.5 Hz
+    const PERIOD: usize = 200;
+
+    // One second of a sine with a quick attack and a slow decay.
+    fn decaying_sine() -> Vec<f32> {
+        (0..SAMPLE_RATE as usize).map(|i| {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            let env = (t / 0.01).min(1.0) * (-t * 1.5).exp();
+            0.5 * env * (2.0 * PI * i as f32 / PERIOD as f32).sin()
+        }).collect()
+    }
+
+    #[test]
+    fn finds_period() {
+        let ss = decaying_sine();
+        let period = find_period(&ss[10000..20000], 20, 1470).unwrap();
+        assert!((period - PERIOD as f64).abs() < 0.1, "{}", period);
+    }
+
+    #[test]
+    fn finds_whole_periods_in_sustain() {
+        let ss = decaying_sine();
+        let l = find_loop(&ss, SAMPLE_RATE).unwrap();
+        assert!(l.start > 4410, "{:?}", l);
+        assert!(l.end > l.start + PERIOD * 10 && l.end <= ss.len(), "{:?}", l);
+        let periods = (l.end - l.start) as f64 / PERIOD as f64;
+        assert!((periods - periods.round()).abs() < 0.01, "{:?}", l);
+    }
+
+    #[test]
+    fn silence_has_no_loop() {
+        assert!(find_loop(&[0.0; 44100], SAMPLE_RATE).is_none());
+    }
+
+    #[test]
+    fn low_rates_have_no_loop() {
+        assert!(find_loop(&decaying_sine(), 50.0).is_none());
+    }
+
+    #[test]
+    fn smpl_round_trips() {
+        let name = format!("music_syn_smpl_round_trips_{}.wav", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let path = path.to_str().unwrap();
+        save_wav(decaying_sine().into_iter(), path, 1).unwrap();
+        assert!(wav_sample_loop(&std::fs::read(path).unwrap()).is_none());
+
+        let l = SampleLoop { start: 10000, end: 30000, alternate: true };
+        write_smpl(path, &l).unwrap();
+        let read = wav_sample_loop(&std::fs::read(path).unwrap()).unwrap();
+        assert_eq!((read.start, read.end, read.alternate), (l.start, l.end, l.alternate));
+        // The audio is left alone.
+        let pcm = load_wav(path).unwrap();
+        assert_eq!(pcm.channels[0].len(), 44100);
+        assert!(pcm.sample_loop.is_some());
+
+        let empty = SampleLoop { start: 10000, end: 10000, alternate: false };
+        assert!(write_smpl(path, &empty).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,8 +1,8 @@
-use crate::soundprim::*;
 use crate::types::*;
 use crate::instr::*;
+use crate::soundprim::{Smoother, pan_gains};
+use crate::damper::{self, Damped, Resonance};
 
-use std::ops::Generator;
 use std::mem;
 use std::collections::HashMap;
 use rimd::{
@@ -14,8 +14,35 @@ use rimd::{
     MetaCommand,
 };
 
-type NoteMap = HashMap<u8, Box<dyn Sound>>;
-type NoteVec = Vec<Box<dyn Sound>>;
+// What a voice plays, and how it is mixed.
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct Note {
+    ch: u8,
+    key: u8,
+    // Through the channel's rotary speaker.
+    rotary: bool,
+}
+
+// Keyed by (channel, key), with the index of the instrument playing it in
+// the channel's registry.
+type NoteMap = HashMap<(u8, u8), (usize, Note, Box<dyn Voice>)>;
+type NoteVec = Vec<(Note, Box<dyn Voice>)>;
+
+pub const NUM_CHANNELS: usize = 16;
+
+// Channel 10 in GM's counting from 1, which plays percussion.
+pub const DRUM_CHANNEL: u8 = 9;
+
+// GM programs played by the sampled piano. The electric pianos (4 and 5)
+// sound better on FM.
+pub const PIANO_PROGRAMS: [u8; 6] = [0, 1, 2, 3, 6, 7];
+
+// Micros per beat
+const DEFAULT_TEMPO: usize = 434_000;
+
+fn is_note(msg: &MidiMessage) -> bool {
+    matches!(msg.status(), MidiStatus::NoteOn | MidiStatus::NoteOff)
+}
 
 pub struct MidiSyn {
     pub sample_rate: f64,
@@ -28,115 +55,437 @@ pub struct MidiSyn {
     // the pedal is released, these sounds are moved to released_sounds.
     dampered_sounds: NoteVec,
 
+    // Stores the released notes of instruments with dampers, which ring on
+    // as far as the damper pedal lets them.
+    string_sounds: Vec<(Note, Damped)>,
+
+    // Stores the notes released while held by the sostenuto pedal. When
+    // the pedal is released, these sounds are moved to dampered_sounds or
+    // released_sounds.
+    sostenuto_sounds: NoteVec,
+
     // Stores the released notes.
     released_sounds: NoteVec,
 
+    // Per channel gain and pan.
+    strips: [Strip; NUM_CHANNELS],
+
     // Stores the fraction part of the sample index.
     sample_ix: f64,
 
+    // Interleaved stereo.
     output: Vec<f32>,
 
-    // Piano syn
-    piano: Piano,
+    // Events to be played by render, and their absolute ticks.
+    events: Vec<TrackEvent>,
+    event_ticks: Vec<u64>,
+    next_event: usize,
+
+    // Ticks at the last and the next scheduling point, i.e. an event or
+    // the loop end. countdown frames are rendered in between.
+    tick: u64,
+    due_tick: u64,
+    countdown: usize,
+
+    // [start, end) in ticks.
+    loop_region: Option<(u64, u64)>,
+
+    // All events are played.
+    ended: bool,
+
+    // By GM program.
+    pub instruments: Registry,
+
+    // By program on DRUM_CHANNEL, as in GM2.
+    pub drum_kits: Registry,
 }
 
-#[derive(Copy, Clone)]
-enum Instrument {
-    Piano,
-    NoImpl,
+// Mixes len samples of each voice into its channel's strip, dropping
+// finished ones.
+fn elapse_vec<V: Voice>(ns: &mut Vec<(Note, V)>, strips: &mut [Strip], len: usize) {
+    ns.retain_mut(|(note, s)| s.render(strips[note.ch as usize].buf(note.rotary, len)));
 }
 
-fn elapse_vec(ns: &mut NoteVec, out: &mut [f32]) {
-    let len = out.len();
-    let mut t = NoteVec::new();
-    mem::swap(ns, &mut t);
-    
-    // For each sample,
-    for mut s in t {
-        // Take len samples
-        let mut empty = false;
-        for i in 0..len {
-            if let Some(v) = s.next() {
-                out[i] += v;
-            } else {
-                empty = true;
-                break;
-            }
+fn elapse_map(ns: &mut NoteMap, strips: &mut [Strip], len: usize) {
+    ns.retain(|_, (_, note, s)| s.render(strips[note.ch as usize].buf(note.rotary, len)));
+}
+
+// Mixes a channel's voices into the stereo bus.
+struct Strip {
+    // Mix of the channel's voices, empty when there are none.
+    buf: Vec<Frame>,
+    // The same for those that play through the rotary speaker.
+    rotary: Vec<Frame>,
+    left: Smoother,
+    right: Smoother,
+    resonance: Resonance,
+    leslie: Leslie,
+}
+
+impl Strip {
+    fn new(state: &ChannelState, sample_rate: f64) -> Self {
+        let (l, r) = state.gains();
+        Self {
+            buf: vec![],
+            rotary: vec![],
+            left: Smoother::new(l, 0.01, sample_rate),
+            right: Smoother::new(r, 0.01, sample_rate),
+            resonance: Resonance::new(sample_rate),
+            leslie: Leslie::new(sample_rate),
         }
-        if !empty {
-            ns.push(s);
+    }
+
+    fn update(&mut self, state: &ChannelState) {
+        let (l, r) = state.gains();
+        self.left.set(l);
+        self.right.set(r);
+    }
+
+    fn buf(&mut self, rotary: bool, len: usize) -> &mut [Frame] {
+        let buf = if rotary { &mut self.rotary } else { &mut self.buf };
+        if buf.is_empty() {
+            buf.resize(len, [0.0, 0.0]);
         }
+        buf
     }
-}
 
-fn elapse_map(ns: &mut NoteMap, out: &mut [f32]) {
-    let len = out.len();
-    let mut t = NoteMap::new();
-    mem::swap(ns, &mut t);
-
-    for (key, mut s) in t {
-        // Take len samples
-        let mut empty = false;
-        for i in 0..len {
-            if let Some(v) = s.next() {
-                out[i] += v;
-            } else {
-                empty = true;
-                break;
-            }
+    // Adds the sympathetic resonance of the strings that the dampers are
+    // lifted off.
+    fn resonate(&mut self, lift: f32, len: usize) {
+        if self.resonance.is_active(lift) {
+            self.buf(false, len);
+            self.resonance.process(&mut self.buf, lift);
         }
-        if !empty {
-            ns.insert(key, s);
+    }
+
+    // Plays the rotary speaker's voices through it, spinning fast or slow.
+    fn rotate(&mut self, fast: bool, len: usize) {
+        if self.rotary.is_empty() && !self.leslie.is_ringing() {
+            // Nothing to hear, but the rotors keep turning.
+            self.leslie.spin(fast, len);
+            return;
+        }
+        self.buf(true, len);
+        self.leslie.process(&mut self.rotary, fast);
+        self.buf(false, len);
+        for (b, r) in self.buf.iter_mut().zip(&self.rotary) {
+            b[0] += r[0];
+            b[1] += r[1];
+        }
+        self.rotary.clear();
+    }
+
+    // Adds the buffered samples to the interleaved out and clears them.
+    fn mix(&mut self, out: &mut [f32]) {
+        if self.buf.is_empty() {
+            self.left.settle();
+            self.right.settle();
+            return;
         }
+        for (v, lr) in self.buf.iter().zip(out.chunks_exact_mut(2)) {
+            lr[0] += v[0] * self.left.next().unwrap();
+            lr[1] += v[1] * self.right.next().unwrap();
+        }
+        self.buf.clear();
     }
 }
 
 impl MidiSyn {
     pub fn new(p: Piano) -> Self {
+        let sample_rate = 44100.0;
+        let mut instruments = Registry::new(SineInstrument { sample_rate });
+        instruments.set(PIANO_PROGRAMS, p);
+        for (programs, preset) in gm_presets() {
+            instruments.set(programs, Synth::new(preset, sample_rate));
+        }
+        for (programs, patch) in gm_patches() {
+            instruments.set(programs, Fm { patch, sample_rate });
+        }
+        for (programs, preset) in gm_plucks() {
+            instruments.set(programs, Pluck::new(preset, sample_rate));
+        }
+        for (program, preset) in gm_organs() {
+            instruments.set([program], Organ::new(preset));
+        }
+        Self::with_instruments(instruments)
+    }
+
+    // Plays the GM programs on the given instruments, and drums on the
+    // synthesized kit.
+    pub fn with_instruments(instruments: Registry) -> Self {
+        let sample_rate = 44100.0;
+        let track_state = TrackState::new();
+        let strips = std::array::from_fn(|ch| {
+            Strip::new(&track_state.channels[ch], sample_rate)
+        });
         Self {
-            sample_rate: 44100.0,
-            track_state: TrackState::new(),
+            sample_rate,
+            track_state,
             sounds: NoteMap::new(),
             dampered_sounds: NoteVec::new(),
+            sostenuto_sounds: NoteVec::new(),
+            string_sounds: vec![],
             released_sounds: NoteVec::new(),
+            strips,
             sample_ix: 0.0,
             output: vec![],
-            piano: p,
+            events: vec![],
+            event_ticks: vec![],
+            next_event: 0,
+            tick: 0,
+            due_tick: 0,
+            countdown: 0,
+            loop_region: None,
+            ended: true,
+            instruments,
+            drum_kits: Registry::new(synth_kit()),
         }
     }
 
-    pub fn syn(&mut self, track: &[TrackEvent]) -> &[f32] {
-        for te in track {
-            self.elapse_ticks(te.vtime);
-            match &te.event {
-                Event::Midi(msg) =>
-                    self.do_midi(msg),
-                Event::Meta(meta) =>
-                    self.do_meta(meta),
+    // Sets the events to be played by render.
+    pub fn load(&mut self, events: Vec<TrackEvent>) {
+        let mut tick = 0;
+        self.event_ticks = events.iter().map(|te| {
+            tick += te.vtime;
+            tick
+        }).collect();
+        self.events = events;
+        self.seek(0);
+    }
+
+    // Fills out with interleaved stereo frames, processing the loaded events
+    // as they become due. Returns false once all events are played and all
+    // sounds have died out.
+    pub fn render(&mut self, out: &mut [f32]) -> bool {
+        out.fill(0.0);
+        let mut out = out;
+        while out.len() >= 2 {
+            self.dispatch_due();
+
+            let frames = if self.ended {
+                out.len() / 2
+            } else {
+                self.countdown.min(out.len() / 2)
+            };
+            let (dst, rest) = out.split_at_mut(frames * 2);
+            self.mix(dst);
+            self.countdown -= frames.min(self.countdown);
+            out = rest;
+        }
+        !self.ended || self.is_sounding()
+    }
+
+    fn dispatch_due(&mut self) {
+        while self.countdown == 0 && !self.ended {
+            self.tick = self.due_tick;
+            if let Some((start, end)) = self.loop_region {
+                if self.tick >= end {
+                    self.seek(start);
+                    continue;
+                }
+            }
+
+            let events = mem::take(&mut self.events);
+            while self.event_ticks.get(self.next_event) == Some(&self.tick) {
+                self.do_event(&events[self.next_event].event);
+                self.next_event += 1;
+            }
+            self.events = events;
+            self.schedule();
+        }
+    }
+
+    // Finds the next scheduling point after self.tick.
+    fn schedule(&mut self) {
+        let next = self.event_ticks.get(self.next_event).cloned();
+        let loop_end = match self.loop_region {
+            Some((_, end)) if self.tick < end => Some(end),
+            _ => None,
+        };
+        let due = match (next, loop_end) {
+            (Some(next), Some(end)) => Some(next.min(end)),
+            (next, end) => next.or(end),
+        };
+        match due {
+            Some(due) => {
+                self.due_tick = due;
+                self.countdown = self.ticks_to_frames(due - self.tick);
+            }
+            None => {
+                // Let everything ring out.
+                self.ended = true;
+                self.release_all();
+            }
+        }
+    }
+
+    // Moves to tick without rendering anything. All but the notes before it
+    // are replayed, so that the tempo, programs, pedals and controllers are
+    // as if played from the start.
+    pub fn seek(&mut self, tick: u64) {
+        self.release_all();
+        for inst in self.instruments.iter_mut().chain(self.drum_kits.iter_mut()) {
+            inst.reset();
+        }
+        let div = self.track_state.div;
+        self.track_state = TrackState::new();
+        self.track_state.div = div;
+        for (strip, state) in self.strips.iter_mut().zip(&self.track_state.channels) {
+            strip.update(state);
+        }
+
+        let events = mem::take(&mut self.events);
+        self.next_event = 0;
+        while self.event_ticks.get(self.next_event).is_some_and(|&t| t < tick) {
+            match &events[self.next_event].event {
+                Event::Midi(msg) if is_note(msg) => {},
+                e => self.do_event(e),
+            }
+            self.next_event += 1;
+        }
+        self.events = events;
+
+        self.tick = tick;
+        self.due_tick = tick;
+        self.countdown = 0;
+        self.sample_ix = 0.0;
+        self.ended = false;
+    }
+
+    // Current position in ticks.
+    pub fn position(&self) -> u64 {
+        let ticks = self.countdown as f64 / self.samples_in_tick(1);
+        self.due_tick.saturating_sub(ticks as u64)
+    }
+
+    // Loops [start, end) in ticks, or stops looping.
+    pub fn set_loop(&mut self, region: Option<(u64, u64)>) {
+        let pos = self.position();
+        self.loop_region = region.filter(|(start, end)| start < end);
+        match self.loop_region {
+            Some((start, end)) if pos < start || pos >= end => self.seek(start),
+            _ => {
+                self.tick = pos;
+                self.ended = false;
+                self.schedule();
             }
         }
-        &self.output
     }
 
-    pub fn syn_gen<'a>(&'a mut self, track: &'a [TrackEvent])
-        -> impl Generator<Yield=Vec<f32>> + Unpin + 'a {
-        self.output.clear();
-        move || {
-            for te in track {
-                let mut v = vec![];
-                self.elapse_ticks(te.vtime);
-                if te.vtime != 0 {
-                    mem::swap(&mut v, &mut self.output);
-                    yield v;
+    // Tick of the given time in seconds, following the tempo changes.
+    pub fn time_to_tick(&self, secs: f64) -> u64 {
+        let div = self.track_state.div as f64;
+        let mut tempo = DEFAULT_TEMPO as f64;
+        let mut tick = 0;
+        let mut t = 0.0;
+        for (te, &at) in self.events.iter().zip(&self.event_ticks) {
+            if let Event::Meta(m) = &te.event {
+                if let MetaCommand::TempoSetting = m.command {
+                    let dt = (at - tick) as f64 * tempo / 1_000_000.0 / div;
+                    if t + dt >= secs {
+                        break;
+                    }
+                    t += dt;
+                    tick = at;
+                    tempo = m.data_as_u64(3) as f64;
                 }
-                match &te.event {
-                    Event::Midi(msg) =>
-                        self.do_midi(msg),
-                    Event::Meta(meta) =>
-                        self.do_meta(meta),
+            }
+        }
+        tick + ((secs - t) * 1_000_000.0 / tempo * div) as u64
+    }
+
+    // Tick where the given bar (1-based) starts, following the time
+    // signature changes.
+    pub fn bar_to_tick(&self, bar: u32) -> u64 {
+        let div = self.track_state.div as u64;
+        if div == 0 {
+            // SMPTE timing has no bars.
+            return 0;
+        }
+        // 4/4 until said otherwise.
+        let mut ticks_per_bar = div * 4;
+        let mut tick = 0;
+        let mut nth_bar = 1;
+        for (te, &at) in self.events.iter().zip(&self.event_ticks) {
+            if let Event::Meta(m) = &te.event {
+                if let MetaCommand::TimeSignature = m.command {
+                    // Numerator and the denominator's power of two. Bars
+                    // shorter than a tick are as good as none.
+                    let next = match m.data[..] {
+                        [num, den, ..] if num > 0 => (div * 4 * num as u64).checked_shr(den as u32),
+                        _ => None,
+                    };
+                    let next = match next {
+                        Some(next) if next > 0 => next,
+                        _ => continue,
+                    };
+                    let bars = (at - tick).div_ceil(ticks_per_bar);
+                    if nth_bar + bars as u32 > bar {
+                        break;
+                    }
+                    tick = at;
+                    nth_bar += bars as u32;
+                    ticks_per_bar = next;
                 }
             }
         }
+        tick + bar.saturating_sub(nth_bar) as u64 * ticks_per_bar
+    }
+
+    fn is_sounding(&self) -> bool {
+        !(self.sounds.is_empty()
+          && self.dampered_sounds.is_empty()
+          && self.sostenuto_sounds.is_empty()
+          && self.string_sounds.is_empty()
+          && self.released_sounds.is_empty())
+    }
+
+    fn release_all(&mut self) {
+        let held = self.sounds.drain().map(|(_, (_, note, s))| (note, s));
+        // The dampers come down on all strings.
+        let strings = self.string_sounds.drain(..).map(|(note, mut s)| {
+            s.set_lift(0.0);
+            (note, Box::new(s) as Box<dyn Voice>)
+        });
+        let dampered = self.dampered_sounds.drain(..)
+            .chain(self.sostenuto_sounds.drain(..))
+            .chain(strings);
+        for (note, mut s) in held.chain(dampered).collect::<Vec<_>>() {
+            // Drums are one-shots, and ring out.
+            if note.ch != DRUM_CHANNEL {
+                s.release();
+            }
+            self.released_sounds.push((note, s));
+        }
+    }
+
+    pub fn syn(&mut self, track: &[TrackEvent]) -> &[f32] {
+        for te in track {
+            self.elapse_ticks(te.vtime);
+            self.do_event(&te.event);
+        }
+        &self.output
+    }
+
+    // Streams the loaded events as interleaved stereo samples, rendering
+    // block_len frames at a time.
+    pub fn samples(self, block_len: usize) -> Samples {
+        assert!(block_len > 0, "block_len must be at least one frame");
+        Samples {
+            syn: self,
+            buf: vec![0.0; block_len * 2],
+            ix: block_len * 2,
+            playing: true,
+        }
+    }
+
+    fn do_event(&mut self, e: &Event) {
+        match e {
+            Event::Midi(msg) =>
+                self.do_midi(msg),
+            Event::Meta(meta) =>
+                self.do_meta(meta),
+        }
     }
 
     fn samples_in_tick(&self, ticks: u64) -> f64 {
@@ -146,35 +495,69 @@ impl MidiSyn {
         ticks as f64 * samples_per_tick
     }
 
-    fn elapse_ticks(&mut self, vt: u64) {
+    // Carries the fraction part over to the next call.
+    fn ticks_to_frames(&mut self, vt: u64) -> usize {
         if vt == 0 {
-            return;
+            return 0;
         }
 
         // Precalc these?
         let nsamples = self.sample_ix + self.samples_in_tick(vt);
         self.sample_ix = nsamples % 1.0;
-        let nsamples = nsamples as usize;
+        nsamples as usize
+    }
 
-        // For each sample,
+    fn elapse_ticks(&mut self, vt: u64) {
+        let nsamples = self.ticks_to_frames(vt);
         let start = self.output.len();
-        self.output.resize(start + nsamples, 0.0);
-        let dst = &mut self.output[start..];
-        // let mut output = vec![0.0; nsamples];
-        // Advance currently ongoing sounds by nsamples.
-        elapse_map(&mut self.sounds, dst);
-        elapse_vec(&mut self.dampered_sounds, dst);
-        elapse_vec(&mut self.released_sounds, dst);
+        self.output.resize(start + nsamples * 2, 0.0);
+
+        let mut output = mem::take(&mut self.output);
+        self.mix(&mut output[start..]);
+        self.output = output;
+    }
+
+    // Advances the ongoing sounds by dst.len() / 2 frames, adding them
+    // to dst.
+    fn mix(&mut self, dst: &mut [f32]) {
+        let nsamples = dst.len() / 2;
+        if nsamples == 0 {
+            return;
+        }
+        elapse_map(&mut self.sounds, &mut self.strips, nsamples);
+        elapse_vec(&mut self.dampered_sounds, &mut self.strips, nsamples);
+        elapse_vec(&mut self.sostenuto_sounds, &mut self.strips, nsamples);
+        elapse_vec(&mut self.string_sounds, &mut self.strips, nsamples);
+        elapse_vec(&mut self.released_sounds, &mut self.strips, nsamples);
+
+        for ch in 0..NUM_CHANNELS {
+            let state = &self.track_state.channels[ch];
+            let lift = match self.instrument(ch as u8).has_dampers() {
+                true => state.damper_lift(),
+                false => 0.0,
+            };
+            let strip = &mut self.strips[ch];
+            strip.resonate(lift, nsamples);
+            strip.rotate(state.leslie_fast(), nsamples);
+        }
+        for strip in &mut self.strips {
+            strip.mix(dst);
+        }
     }
 
     fn do_midi(&mut self, msg: &MidiMessage) {
         use self::MidiStatus::*;
 
+        let ch = msg.data[0] & 0x0f;
         match msg.status() {
-            NoteOn => self.do_note_on(msg.data[1], msg.data[2]),
-            NoteOff => self.do_note_off(msg.data[1]),
-            ProgramChange => self.do_prog_change(msg.data[1]),
-            ControlChange => self.do_ctrl_change(msg.data[1], msg.data[2]),
+            NoteOn => self.do_note_on(ch, msg.data[1], msg.data[2]),
+            NoteOff => self.do_note_off(ch, msg.data[1]),
+            ProgramChange => self.do_prog_change(ch, msg.data[1]),
+            ControlChange => self.do_ctrl_change(ch, msg.data[1], msg.data[2]),
+            PitchBend => {
+                let bend = (msg.data[2] as u16) << 7 | msg.data[1] as u16;
+                self.do_pitch_bend(ch, bend);
+            }
             _ => {},
         }
     }
@@ -199,76 +582,247 @@ impl MidiSyn {
         }
     }
 
-    fn do_note_on(&mut self, key: u8, velo: u8) {
-        if velo == 0 {
-            return self.do_note_off(key)
+    // The instrument playing ch's program.
+    fn registry(&self, ch: u8) -> &Registry {
+        if ch == DRUM_CHANNEL {
+            &self.drum_kits
+        } else {
+            &self.instruments
         }
-        if self.sounds.contains_key(&key) {
-            // Assume that the intention is to re-press this key.
-            self.do_note_off(key);
+    }
+
+    fn registry_mut(&mut self, ch: u8) -> &mut Registry {
+        if ch == DRUM_CHANNEL {
+            &mut self.drum_kits
+        } else {
+            &mut self.instruments
         }
+    }
 
-        let key_wrt_c4 = (key as i32) - 60;
-        let duration = 1.0;
-        let amp = (velo as f64) / 128.0;
+    // The instrument of the channel's current program.
+    fn instrument(&self, ch: u8) -> &dyn Instrument {
+        let program = self.track_state.channels[ch as usize].program;
+        self.registry(ch).get(program)
+    }
 
-        let ss: Box<dyn Sound> = match self.track_state.instrument {
-            Instrument::Piano => {
-                Box::new(self.piano.syn(key_wrt_c4, amp))
+    // Cuts off the held keys of ch that are in key's choke group, key
+    // included.
+    fn choke(&mut self, ch: u8, key: u8) {
+        let inst = self.instrument(ch);
+        let group = match inst.choke_group(key) {
+            Some(group) => group,
+            None => return,
+        };
+        let choked: Vec<_> = self.sounds.keys()
+            .filter(|&&(sch, k)| sch == ch && inst.choke_group(k) == Some(group))
+            .cloned()
+            .collect();
+        for k in choked {
+            if let Some((_, note, mut s)) = self.sounds.remove(&k) {
+                s.choke();
+                self.released_sounds.push((note, s));
             }
-            _ => {
-                let synthesizer = Sine {
-                    key: key_wrt_c4,
-                    duration,
-                    amp,
-                    sample_rate: self.sample_rate,
-                };
-                Box::new(synthesizer.syn())
+        }
+    }
+
+    // Lifts the dampers of ch's released strings as far as its pedals do.
+    fn lift_strings(&mut self, ch: u8) {
+        let state = &self.track_state.channels[ch as usize];
+        for (note, s) in self.string_sounds.iter_mut() {
+            if note.ch == ch {
+                s.set_lift(state.string_lift(note.key));
             }
+        }
+    }
+
+    fn do_note_on(&mut self, ch: u8, key: u8, velo: u8) {
+        if velo == 0 {
+            return self.do_note_off(ch, key)
+        }
+        self.choke(ch, key);
+        if ch == DRUM_CHANNEL {
+            // Let the last hit ring out.
+            if let Some((_, note, s)) = self.sounds.remove(&(ch, key)) {
+                self.released_sounds.push((note, s));
+            }
+        } else if self.sounds.contains_key(&(ch, key)) {
+            // Assume that the intention is to re-press this key.
+            self.do_note_off(ch, key);
+        }
+
+        let program = self.track_state.channels[ch as usize].program;
+        let ix = self.registry(ch).index_of(program);
+        let rotary = self.registry(ch).at(ix).has_leslie();
+        let mut ss = match self.registry_mut(ch).at_mut(ix).note_on(ch, key, velo) {
+            Some(ss) => ss,
+            None => return,
         };
 
-        self.sounds.insert(key, ss);
+        let ratio = self.track_state.channels[ch as usize].pitch_bend_ratio();
+        if ratio != 1.0 {
+            ss.set_pitch_bend(ratio);
+        }
+        self.sounds.insert((ch, key), (ix, Note { ch, key, rotary }, ss));
     }
 
-    fn do_note_off(&mut self, key: u8) {
-        if let Some(ss) = self.sounds.remove(&key) {
-            if self.track_state.damper_pedal {
+    fn do_note_off(&mut self, ch: u8, key: u8) {
+        if ch == DRUM_CHANNEL {
+            // Drums are one-shots.
+            return;
+        }
+        if let Some((ix, note, mut ss)) = self.sounds.remove(&(ch, key)) {
+            // To the instrument that played it, whatever the program is now.
+            self.registry_mut(ch).at_mut(ix).note_off(ch, key);
+            let dampers = self.registry(ch).at(ix).has_dampers();
+            let state = &self.track_state.channels[ch as usize];
+            if dampers {
+                // Even with the pedal up, the dampers take a moment.
+                let lift = state.string_lift(key);
+                self.string_sounds.push((note, Damped::new(ss, lift)));
+            } else if state.sostenuto_holds(key) {
+                self.sostenuto_sounds.push((note, ss));
+            } else if state.damper_pedal() {
                 // Move to the dampered sounds.
-                self.dampered_sounds.push(ss);
+                self.dampered_sounds.push((note, ss));
             } else {
-                let env = Envelope::just_release();
-                self.released_sounds.push(Box::new(env.mult(ss, 0.1)));
+                ss.release();
+                self.released_sounds.push((note, ss));
             }
         }
     }
 
-    fn do_prog_change(&mut self, preset: u8) {
-        let instr = if preset <= 7 {
-            // Generic piano for 0-7
-            Instrument::Piano
-        } else {
-            println!("Unsupported ProgChange(preset={})", preset);
-            Instrument::NoImpl
-        };
-        self.track_state.instrument = instr;
-    }
-
-    fn do_ctrl_change(&mut self, ctrl: u8, option: u8) {
-        if ctrl == 64 {
-            let on = option >= 64;
-            if self.track_state.damper_pedal != on {
-                // Releasing damper pedal: apply to existing sounds.
-                if !on {
-                    let mut ss = vec![];
-                    mem::swap(&mut self.dampered_sounds, &mut ss);
-                    let env = Envelope::just_release();
-                    for s in ss {
-                        self.released_sounds.push(Box::new(env.mult(s, 0.1)));
+    fn do_pitch_bend(&mut self, ch: u8, bend: u16) {
+        let state = &mut self.track_state.channels[ch as usize];
+        state.pitch_bend = bend;
+        let ratio = state.pitch_bend_ratio();
+
+        // Applies to everything still sounding on this channel.
+        let held = self.sounds.iter_mut()
+            .filter(|((sch, _), _)| *sch == ch)
+            .map(|(_, (_, _, s))| s);
+        let released = self.dampered_sounds.iter_mut()
+            .chain(self.sostenuto_sounds.iter_mut())
+            .chain(self.released_sounds.iter_mut())
+            .filter(|(note, _)| note.ch == ch)
+            .map(|(_, s)| s);
+        for s in held.chain(released) {
+            s.set_pitch_bend(ratio);
+        }
+        for (_, s) in self.string_sounds.iter_mut().filter(|(note, _)| note.ch == ch) {
+            s.set_pitch_bend(ratio);
+        }
+    }
+
+    fn do_prog_change(&mut self, ch: u8, preset: u8) {
+        if ch != DRUM_CHANNEL && !self.instruments.has(preset) {
+            println!("Unsupported ProgChange(ch={}, preset={})", ch, preset);
+        }
+        self.track_state.channels[ch as usize].program = preset;
+    }
+
+    fn do_ctrl_change(&mut self, ch: u8, ctrl: u8, option: u8) {
+        for inst in self.instruments.iter_mut().chain(self.drum_kits.iter_mut()) {
+            inst.control_change(ch, ctrl, option);
+        }
+
+        let state = &mut self.track_state.channels[ch as usize];
+        match ctrl {
+            // Bank select MSB.
+            0 => state.bank = option,
+            1 => state.modulation = option,
+            7 => {
+                state.volume = option;
+                self.strips[ch as usize].update(state);
+            }
+            10 => {
+                state.pan = option;
+                self.strips[ch as usize].update(state);
+            }
+            11 => {
+                state.expression = option;
+                self.strips[ch as usize].update(state);
+            }
+            64 => {
+                let was_down = state.damper_pedal();
+                state.damper = option;
+                // Releasing damper pedal: apply to this channel's sounds.
+                if was_down && !state.damper_pedal() {
+                    let ss = mem::take(&mut self.dampered_sounds);
+                    for (note, mut s) in ss {
+                        if note.ch == ch {
+                            s.release();
+                            self.released_sounds.push((note, s));
+                        } else {
+                            self.dampered_sounds.push((note, s));
+                        }
                     }
                 }
+                // Strings ring as far as the dampers are lifted, and those
+                // still ringing are caught when they're lifted again.
+                self.lift_strings(ch);
             }
-            self.track_state.damper_pedal = on;
+            66 => {
+                let on = option >= 64;
+                if state.sostenuto_pedal != on {
+                    if on {
+                        // Only holds the keys that are down right now. Those
+                        // already released are left to the damper pedal.
+                        state.sostenuto_keys = self.sounds.keys()
+                            .filter(|(sch, _)| *sch == ch)
+                            .fold(0, |keys, (_, key)| keys | 1 << key);
+                    } else {
+                        // Hand over to the damper pedal if it's still down.
+                        let damper_pedal = state.damper_pedal();
+                        state.sostenuto_keys = 0;
+                        let ss = mem::take(&mut self.sostenuto_sounds);
+                        for (note, mut s) in ss {
+                            if note.ch != ch {
+                                self.sostenuto_sounds.push((note, s));
+                            } else if damper_pedal {
+                                self.dampered_sounds.push((note, s));
+                            } else {
+                                s.release();
+                                self.released_sounds.push((note, s));
+                            }
+                        }
+                    }
+                }
+                state.sostenuto_pedal = on;
+                self.lift_strings(ch);
+            }
+            // Registered and non-registered parameter numbers. Data entry
+            // only goes to the selected RPN, so selecting an NRPN deselects it.
+            101 => state.rpn = (option as u16) << 7 | (state.rpn & 0x7f),
+            100 => state.rpn = (state.rpn & !0x7f) | option as u16,
+            98 | 99 => state.rpn = RPN_NULL,
+            6 => state.data_entry(option, None),
+            38 => state.data_entry(state.bend_range.0, Some(option)),
+            _ => {},
+        }
+    }
+}
+
+pub struct Samples {
+    syn: MidiSyn,
+    buf: Vec<f32>,
+    // Next sample in buf.
+    ix: usize,
+    playing: bool,
+}
+
+impl Iterator for Samples {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.ix == self.buf.len() {
+            if !self.playing {
+                return None;
+            }
+            self.playing = self.syn.render(&mut self.buf);
+            self.ix = 0;
         }
+        self.ix += 1;
+        Some(self.buf[self.ix - 1])
     }
 }
 
@@ -279,19 +833,315 @@ pub struct TrackState {
     // Micros per beat
     tempo: usize,
 
-    instrument: Instrument,
-
-    damper_pedal: bool,
+    pub channels: [ChannelState; NUM_CHANNELS],
 }
 
 impl TrackState {
     fn new() -> Self {
         Self {
             div: 480,
-            tempo: 434_000,
-            instrument: Instrument::Piano,
-            damper_pedal: false,
+            tempo: DEFAULT_TEMPO,
+            channels: [ChannelState::new(); NUM_CHANNELS],
         }
     }
 }
 
+// Per MIDI channel state, in raw MIDI values.
+#[derive(Copy, Clone)]
+pub struct ChannelState {
+    // GM program number
+    pub program: u8,
+
+    // CC 0
+    pub bank: u8,
+
+    // CC 1
+    pub modulation: u8,
+
+    // CC 64
+    pub damper: u8,
+
+    // CC 66
+    pub sostenuto_pedal: bool,
+
+    // Keys held when the sostenuto pedal went down, one bit each.
+    pub sostenuto_keys: u128,
+
+    // CC 7
+    pub volume: u8,
+
+    // CC 11
+    pub expression: u8,
+
+    // CC 10, 64 is center
+    pub pan: u8,
+
+    // 14 bits, 8192 is center
+    pub pitch_bend: u16,
+
+    // Pitch bend sensitivity as (semitones, cents), set through RPN 0.
+    pub bend_range: (u8, u8),
+
+    // Currently selected RPN, 14 bits
+    pub rpn: u16,
+}
+
+const RPN_NULL: u16 = 0x3fff;
+const RPN_BEND_RANGE: u16 = 0;
+
+impl ChannelState {
+    fn new() -> Self {
+        Self {
+            program: 0,
+            bank: 0,
+            modulation: 0,
+            damper: 0,
+            sostenuto_pedal: false,
+            sostenuto_keys: 0,
+            volume: 100,
+            expression: 127,
+            pan: 64,
+            pitch_bend: 8192,
+            bend_range: (2, 0),
+            rpn: RPN_NULL,
+        }
+    }
+
+    // Whether the damper pedal holds notes, for instruments without
+    // dampers.
+    pub fn damper_pedal(&self) -> bool {
+        self.damper >= 64
+    }
+
+    // Whether the mod wheel is at least halfway, which spins the rotary
+    // speaker fast.
+    pub fn leslie_fast(&self) -> bool {
+        self.modulation >= 64
+    }
+
+    // How far the damper pedal lifts the dampers, from 0 to 1.
+    pub fn damper_lift(&self) -> f32 {
+        damper::lift(self.damper)
+    }
+
+    // Whether the sostenuto pedal holds the key's damper up.
+    pub fn sostenuto_holds(&self, key: u8) -> bool {
+        self.sostenuto_pedal && self.sostenuto_keys & 1 << key != 0
+    }
+
+    // How far the key's damper is lifted, by either pedal.
+    pub fn string_lift(&self, key: u8) -> f32 {
+        match self.sostenuto_holds(key) {
+            true => 1.0,
+            false => self.damper_lift(),
+        }
+    }
+
+    fn data_entry(&mut self, msb: u8, lsb: Option<u8>) {
+        if self.rpn == RPN_BEND_RANGE {
+            self.bend_range = (msb, lsb.unwrap_or(0));
+        }
+    }
+
+    // (left, right) gains from volume, expression and pan.
+    pub fn gains(&self) -> (f32, f32) {
+        // GM curves: 40 log10(value / 127) dB each.
+        let volume = (self.volume as f32 / 127.).powi(2);
+        let expression = (self.expression as f32 / 127.).powi(2);
+        // Both 0 and 1 are hard left.
+        let (l, r) = pan_gains((self.pan.max(1) - 1) as f32 / 126.);
+        let gain = volume * expression;
+        (l * gain, r * gain)
+    }
+
+    // Frequency multiplier of the current pitch bend.
+    pub fn pitch_bend_ratio(&self) -> f64 {
+        let range = self.bend_range.0 as f64 + self.bend_range.1 as f64 / 100.0;
+        let semitones = (self.pitch_bend as f64 - 8192.0) / 8192.0 * range;
+        2f64.powf(semitones / 12.0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instr::Tone;
+
+    const DAMPER: u8 = 64;
+    const SOSTENUTO: u8 = 66;
+
+    // Steady tones, played as strings with dampers or not, or through a
+    // rotary speaker.
+    struct Tones {
+        dampers: bool,
+        leslie: bool,
+    }
+
+    impl Instrument for Tones {
+        fn note_on(&mut self, _ch: u8, _key: u8, _velo: u8) -> Option<Box<dyn Voice>> {
+            Some(Box::new(Tone::new(0.1, self.dampers)))
+        }
+
+        fn has_dampers(&self) -> bool {
+            self.dampers
+        }
+
+        fn has_leslie(&self) -> bool {
+            self.leslie
+        }
+    }
+
+    fn syn(dampers: bool) -> MidiSyn {
+        MidiSyn::with_instruments(Registry::new(Tones { dampers, leslie: false }))
+    }
+
+    fn play(syn: &mut MidiSyn, secs: f64) -> Vec<f32> {
+        let mut out = vec![0.0; (syn.sample_rate * secs) as usize * 2];
+        syn.mix(&mut out);
+        out
+    }
+
+    fn cc(syn: &mut MidiSyn, ctrl: u8, value: u8) {
+        syn.do_midi(&MidiMessage::control_change(ctrl, value, 0));
+    }
+
+    fn press(syn: &mut MidiSyn, key: u8) {
+        syn.do_midi(&MidiMessage::note_on(key, 100, 0));
+        play(syn, 0.1);
+        syn.do_midi(&MidiMessage::note_off(key, 0, 0));
+    }
+
+    // Keys of channel 0 still sounding.
+    fn sounding(syn: &MidiSyn) -> Vec<u8> {
+        let held = syn.sounds.values().map(|&(_, note, _)| note);
+        let ringing = syn.dampered_sounds.iter()
+            .chain(&syn.sostenuto_sounds)
+            .chain(&syn.released_sounds)
+            .map(|&(note, _)| note);
+        let strings = syn.string_sounds.iter().map(|&(note, _)| note);
+        let mut keys: Vec<_> = held.chain(ringing).chain(strings)
+            .filter(|note| note.ch == 0)
+            .map(|note| note.key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    // A note released under the damper pedal, then the sostenuto pedal
+    // pressed while another is down.
+    fn sostenuto_after_damper(dampers: bool) {
+        let mut syn = syn(dampers);
+        cc(&mut syn, DAMPER, 127);
+        press(&mut syn, 60);
+        syn.do_midi(&MidiMessage::note_on(62, 100, 0));
+        cc(&mut syn, SOSTENUTO, 127);
+        syn.do_midi(&MidiMessage::note_off(62, 0, 0));
+        play(&mut syn, 0.5);
+        assert_eq!(sounding(&syn), [60, 62]);
+
+        // The damper pedal lets go of the note it was holding.
+        cc(&mut syn, DAMPER, 0);
+        play(&mut syn, 0.5);
+        assert_eq!(sounding(&syn), [62]);
+
+        cc(&mut syn, SOSTENUTO, 0);
+        play(&mut syn, 0.5);
+        assert_eq!(sounding(&syn), []);
+    }
+
+    #[test]
+    fn sostenuto_leaves_dampered_notes() {
+        sostenuto_after_damper(false);
+    }
+
+    #[test]
+    fn sostenuto_leaves_ringing_strings() {
+        sostenuto_after_damper(true);
+    }
+
+    fn time_signature(vtime: u64, data: Vec<u8>) -> TrackEvent {
+        let meta = MetaEvent { command: MetaCommand::TimeSignature, length: data.len() as u64, data };
+        TrackEvent { vtime, event: Event::Meta(meta) }
+    }
+
+    #[test]
+    fn bars_follow_time_signatures() {
+        let mut syn = syn(false);
+        // 4/4 for two bars, then 6/8.
+        syn.load(vec![
+            time_signature(0, vec![4, 2, 24, 8]),
+            time_signature(3840, vec![6, 3, 24, 8]),
+        ]);
+        assert_eq!(syn.bar_to_tick(1), 0);
+        assert_eq!(syn.bar_to_tick(2), 1920);
+        assert_eq!(syn.bar_to_tick(3), 3840);
+        assert_eq!(syn.bar_to_tick(5), 3840 + 2 * 1440);
+    }
+
+    #[test]
+    fn bars_skip_bad_time_signatures() {
+        let mut syn = syn(false);
+        syn.load(vec![
+            time_signature(0, vec![0, 2, 24, 8]),
+            time_signature(0, vec![4, 64, 24, 8]),
+            time_signature(0, vec![4, 40, 24, 8]),
+            time_signature(0, vec![4]),
+        ]);
+        assert_eq!(syn.bar_to_tick(3), 3840);
+
+        syn.track_state.div = 0;
+        assert_eq!(syn.bar_to_tick(3), 0);
+    }
+
+    #[test]
+    fn only_rotary_voices_go_through_the_leslie() {
+        const ORGAN: u8 = 16;
+        let is_dry = |out: &[f32]| out.chunks(2).all(|lr| lr[0] == lr[1]);
+        let mut syn = syn(false);
+        syn.instruments.set([ORGAN], Tones { dampers: false, leslie: true });
+        syn.do_midi(&MidiMessage::note_on(60, 100, 0));
+        syn.do_midi(&MidiMessage::program_change(ORGAN, 0));
+        assert!(is_dry(&play(&mut syn, 0.5)));
+
+        syn.do_midi(&MidiMessage::note_on(62, 100, 0));
+        assert!(!is_dry(&play(&mut syn, 0.5)));
+
+        // Once rung out, the strip is left alone.
+        syn.do_midi(&MidiMessage::note_off(62, 0, 0));
+        play(&mut syn, 0.1);
+        play(&mut syn, 0.1);
+        assert!(!syn.strips[0].leslie.is_ringing());
+        assert!(is_dry(&play(&mut syn, 0.5)));
+    }
+
+    #[test]
+    fn releasing_all_damps_strings() {
+        let mut syn = syn(true);
+        cc(&mut syn, DAMPER, 127);
+        press(&mut syn, 60);
+        syn.release_all();
+        play(&mut syn, 0.5);
+        assert_eq!(sounding(&syn), []);
+    }
+
+    #[test]
+    fn sostenuto_holds_down_keys() {
+        let mut syn = syn(true);
+        syn.do_midi(&MidiMessage::note_on(60, 100, 0));
+        cc(&mut syn, SOSTENUTO, 127);
+        syn.do_midi(&MidiMessage::note_off(60, 0, 0));
+        press(&mut syn, 62);
+        play(&mut syn, 0.5);
+        assert_eq!(sounding(&syn), [60]);
+
+        // Strings already damped stay so.
+        cc(&mut syn, SOSTENUTO, 0);
+        press(&mut syn, 64);
+        play(&mut syn, 0.5);
+        cc(&mut syn, SOSTENUTO, 127);
+        cc(&mut syn, DAMPER, 127);
+        play(&mut syn, 0.5);
+        assert_eq!(sounding(&syn), []);
+    }
+}
@@ -0,0 +1,149 @@
+use std::f64::consts::PI;
+use crate::types::Sound;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    // Width set by Oscillator::width.
+    Pulse,
+}
+
+// PolyBLEP residual: the difference between a band-limited step and the
+// naive one, for a step at phase 0. dt is the phase increment per sample.
+fn poly_blep(t: f64, dt: f64) -> f64 {
+    if t < dt {
+        let t = t / dt;
+        2. * t - t * t - 1.
+    } else if t > 1. - dt {
+        let t = (t - 1.) / dt;
+        t * t + 2. * t + 1.
+    } else {
+        0.
+    }
+}
+
+// Band-limited oscillator. Saw and pulse edges are smoothed with PolyBLEP,
+// triangle is the integral of the smoothed square. Output is in [-1, 1].
+pub struct Oscillator {
+    pub wave: Waveform,
+    // Hz
+    pub freq: f64,
+    // Fraction of the period that a Pulse is high, 0.5 being a square.
+    pub width: f64,
+    sample_rate: f64,
+    // In cycles, [0, 1).
+    phase: f64,
+    // Integrator state of the triangle.
+    tri: f64,
+}
+
+impl Oscillator {
+    pub fn new(wave: Waveform, freq: f64, sample_rate: f64) -> Self {
+        Self {
+            wave,
+            freq,
+            width: 0.5,
+            sample_rate,
+            phase: 0.,
+            tri: -1.,
+        }
+    }
+
+    // Starts at the given phase in cycles, e.g. to detune oscillators
+    // without them starting in phase.
+    pub fn with_phase(mut self, phase: f64) -> Self {
+        self.phase = phase.rem_euclid(1.);
+        // The triangle rises while the square is high, from -1 at 0.
+        self.tri = 1. - 4. * (self.phase - 0.5).abs();
+        self
+    }
+
+    // The next sample, at freq Hz. Changing freq from sample to sample
+    // gives continuous frequency modulation.
+    pub fn tick(&mut self, freq: f64) -> f32 {
+        let dt = (freq / self.sample_rate).clamp(0., 0.5);
+        let t = self.phase;
+        let v = match self.wave {
+            Waveform::Sine => (2. * PI * t).sin(),
+            Waveform::Saw => 2. * t - 1. - poly_blep(t, dt),
+            Waveform::Square => self.pulse(t, dt, 0.5),
+            Waveform::Pulse => self.pulse(t, dt, self.width.clamp(0.01, 0.99)),
+            Waveform::Triangle => {
+                // Leaks a little to not drift away from 0.
+                let square = self.pulse(t, dt, 0.5);
+                self.tri = self.tri * (1. - dt * 0.01) + 4. * dt * square;
+                self.tri
+            }
+        };
+        self.phase = (self.phase + dt) % 1.;
+        v as f32
+    }
+
+    // Without the DC offset of uneven widths, scaled back into [-1, 1].
+    fn pulse(&self, t: f64, dt: f64, width: f64) -> f64 {
+        let naive = if t < width { 1. } else { -1. };
+        let dc = 2. * width - 1.;
+        let v = naive + poly_blep(t, dt) - poly_blep((t + 1. - width) % 1., dt);
+        (v - dc) / (1. + dc.abs())
+    }
+
+    // Exponential frequency modulation: each sample of fm shifts the
+    // frequency by that many octaves, as for vibrato.
+    pub fn fm(mut self, fm: impl Sound) -> impl Sound {
+        fm.map(move |octaves| {
+            let freq = self.freq * 2f64.powf(octaves as f64);
+            self.tick(freq)
+        })
+    }
+}
+
+impl Iterator for Oscillator {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        Some(self.tick(self.freq))
+    }
+}
+
+// White noise in [-1, 1), from a xorshift generator.
+pub struct Noise(u32);
+
+impl Noise {
+    pub fn new(seed: u32) -> Self {
+        // The state must never be 0.
+        Noise(seed.max(1))
+    }
+}
+
+impl Iterator for Noise {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        Some(x as f32 / 2_147_483_648.0 - 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triangle_starts_at_its_phase() {
+        for phase in [0., 0.25, 0.5, 0.8] {
+            let mut osc = Oscillator::new(Waveform::Triangle, 441., 44100.).with_phase(phase);
+            let ss: Vec<f32> = (0..1000).map(|_| osc.tick(441.)).collect();
+            let (lo, hi) = ss.iter().fold((0f32, 0f32), |(lo, hi), &x| (lo.min(x), hi.max(x)));
+            assert!(lo > -1.05 && hi < 1.05 && hi - lo > 1.9, "{} {} {}", phase, lo, hi);
+            let mean = ss.iter().sum::<f32>() / ss.len() as f32;
+            assert!(mean.abs() < 0.05, "{} {}", phase, mean);
+        }
+    }
+}
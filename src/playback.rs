@@ -1,11 +1,16 @@
 use portaudio as pa;
 use crate::types::SoundRef;
-use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
 pub struct Settings {
     pub channels: i32,
     pub sample_rate: f64,
     pub frames_per_buffer: u32,
+    // Frames rendered ahead of the audio callback.
+    pub ring_frames: usize,
 }
 
 impl Settings {
@@ -14,55 +19,235 @@ impl Settings {
             channels: 1,
             sample_rate: 44_100.0,
             frames_per_buffer: 64,
+            ring_frames: 4096,
         }
     }
 }
 
-pub fn play_def(sound: impl SoundRef) -> Result<(), pa::Error> {
-    play(&Settings::default(), sound)
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stats {
+    // Number of callbacks that ran out of rendered samples.
+    pub underruns: u64,
+    // Frames filled with silence because of underruns.
+    pub underrun_frames: u64,
 }
 
-pub fn play(settings: &Settings,
-            sound: impl SoundRef) -> Result<(), pa::Error> {
+// Single producer single consumer ring of interleaved samples. Only whole
+// frames go in and out. Samples are kept as f32 bits in atomics so that
+// neither side ever blocks or needs unsafe code.
+struct Ring {
+    buf: Vec<AtomicU32>,
+    channels: usize,
+    // Total samples written and read. Only ever increase.
+    written: AtomicUsize,
+    read: AtomicUsize,
+}
 
-    // We know that the sound will not be used after this function returns,
-    // so this cast of lifetime is valid.
-    let bsound: Box<dyn SoundRef> = Box::new(sound);
-    let mut sound: Box<dyn SoundRef> = unsafe { mem::transmute(bsound) };
+impl Ring {
+    fn new(frames: usize, channels: usize) -> Self {
+        Self {
+            buf: (0..frames * channels).map(|_| AtomicU32::new(0)).collect(),
+            channels,
+            written: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+        }
+    }
 
-    let pa = pa::PortAudio::new()?;
+    // Returns the number of samples taken from xs.
+    fn push(&self, xs: &[f32]) -> usize {
+        let w = self.written.load(Ordering::Relaxed);
+        let r = self.read.load(Ordering::Acquire);
+        let n = self.whole_frames(xs.len().min(self.buf.len() - (w - r)));
+        for (i, x) in xs[..n].iter().enumerate() {
+            self.buf[(w + i) % self.buf.len()].store(x.to_bits(), Ordering::Relaxed);
+        }
+        self.written.store(w + n, Ordering::Release);
+        n
+    }
 
-    let mut pa_settings = pa.default_output_stream_settings(
-        settings.channels,
-        settings.sample_rate,
-        settings.frames_per_buffer)?;
-    pa_settings.flags = pa::stream_flags::CLIP_OFF;
+    // Returns the number of samples written to out.
+    fn pop(&self, out: &mut [f32]) -> usize {
+        let r = self.read.load(Ordering::Relaxed);
+        let w = self.written.load(Ordering::Acquire);
+        let n = self.whole_frames(out.len().min(w - r));
+        for (i, o) in out[..n].iter_mut().enumerate() {
+            *o = f32::from_bits(self.buf[(r + i) % self.buf.len()].load(Ordering::Relaxed));
+        }
+        self.read.store(r + n, Ordering::Release);
+        n
+    }
 
-    let callback = move |args: pa::OutputStreamCallbackArgs<_>| {
-        let buffer = args.buffer;
+    fn whole_frames(&self, samples: usize) -> usize {
+        samples - samples % self.channels
+    }
+}
 
-        for b in buffer {
-            if let Some(v) = sound.next() {
-                *b = v;
-            } else {
-                return pa::Complete
+// State shared by the render thread, the audio callback and the Player.
+struct Shared {
+    ring: Ring,
+    // Set by the render thread once the sound has ended.
+    done: AtomicBool,
+    // Asks the render thread to quit.
+    stop: AtomicBool,
+    underruns: AtomicU64,
+    underrun_frames: AtomicU64,
+}
+
+// Fills the ring from the sound until it ends or we are asked to stop.
+fn render_loop(mut sound: impl SoundRef, shared: &Shared, chunk: usize) {
+    let mut buf = Vec::with_capacity(chunk);
+    loop {
+        buf.clear();
+        buf.extend(sound.by_ref().take(chunk));
+        let mut pushed = 0;
+        while shared.ring.whole_frames(buf.len() - pushed) > 0 {
+            if shared.stop.load(Ordering::Relaxed) {
+                return;
+            }
+            pushed += shared.ring.push(&buf[pushed..]);
+            if pushed < buf.len() {
+                // Full: wait for the callback to catch up.
+                thread::sleep(Duration::from_millis(1));
             }
         }
-        pa::Continue
-    };
+        if buf.len() < chunk {
+            shared.done.store(true, Ordering::Release);
+            return;
+        }
+    }
+}
+
+// A running playback. Samples are rendered on a dedicated thread ahead of
+// the audio callback, which only copies them out of a ring buffer.
+pub struct Player {
+    pa: pa::PortAudio,
+    stream: pa::Stream<pa::NonBlocking, pa::Output<f32>>,
+    shared: Arc<Shared>,
+    render_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Player {
+    pub fn start(settings: &Settings,
+                 sound: impl SoundRef + Send + 'static) -> Result<Self, pa::Error> {
+        let channels = settings.channels as usize;
+        let shared = Arc::new(Shared {
+            ring: Ring::new(settings.ring_frames, channels),
+            done: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+            underruns: AtomicU64::new(0),
+            underrun_frames: AtomicU64::new(0),
+        });
 
-    let mut stream = pa.open_non_blocking_stream(pa_settings, callback)?;
+        let chunk = settings.frames_per_buffer as usize * channels;
+        let render_shared = shared.clone();
+        let render_thread = thread::spawn(move || {
+            render_loop(sound, &render_shared, chunk);
+        });
 
-    stream.start()?;
+        let opened = pa::PortAudio::new().and_then(|pa| {
+            let stream = Self::open(&pa, settings, shared.clone())?;
+            Ok((pa, stream))
+        });
+        let (pa, stream) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                shared.stop.store(true, Ordering::Relaxed);
+                let _ = render_thread.join();
+                return Err(e);
+            }
+        };
 
-    while stream.is_active()? {
-        pa.sleep(100);
+        // From here on, dropping the player stops the render thread.
+        let mut player = Player {
+            pa,
+            stream,
+            shared,
+            render_thread: Some(render_thread),
+        };
+        player.stream.start()?;
+        Ok(player)
     }
 
-    stream.stop()?;
-    stream.close()?;
+    fn open(pa: &pa::PortAudio, settings: &Settings, shared: Arc<Shared>)
+        -> Result<pa::Stream<pa::NonBlocking, pa::Output<f32>>, pa::Error> {
+        let channels = settings.channels as usize;
+        let mut pa_settings = pa.default_output_stream_settings(
+            settings.channels,
+            settings.sample_rate,
+            settings.frames_per_buffer)?;
+        pa_settings.flags = pa::stream_flags::CLIP_OFF;
+
+        let callback = move |args: pa::OutputStreamCallbackArgs<f32>| {
+            let buffer = args.buffer;
+            // Check before popping, so that nothing pushed in between is lost.
+            let done = shared.done.load(Ordering::Acquire);
+            let n = shared.ring.pop(buffer);
+            for b in &mut buffer[n..] {
+                *b = 0.0;
+            }
+            if n < buffer.len() {
+                if done {
+                    return pa::Complete;
+                }
+                shared.underruns.fetch_add(1, Ordering::Relaxed);
+                shared.underrun_frames.fetch_add(
+                    ((buffer.len() - n) / channels) as u64, Ordering::Relaxed);
+            }
+            pa::Continue
+        };
+
+        pa.open_non_blocking_stream(pa_settings, callback)
+    }
+
+    pub fn is_active(&self) -> Result<bool, pa::Error> {
+        self.stream.is_active()
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            underruns: self.shared.underruns.load(Ordering::Relaxed),
+            underrun_frames: self.shared.underrun_frames.load(Ordering::Relaxed),
+        }
+    }
+
+    // Blocks until the sound has been played to the end.
+    pub fn wait(self) -> Result<Stats, pa::Error> {
+        while self.stream.is_active()? {
+            self.pa.sleep(100);
+        }
+        self.stop()
+    }
+
+    pub fn stop(mut self) -> Result<Stats, pa::Error> {
+        self.stream.stop()?;
+        self.stream.close()?;
+        self.join();
+        Ok(self.stats())
+    }
+
+    fn join(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.render_thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+pub fn play_def(sound: impl SoundRef + Send + 'static) -> Result<Stats, pa::Error> {
+    play(&Settings::default(), sound)
+}
+
+pub fn play(settings: &Settings,
+            sound: impl SoundRef + Send + 'static) -> Result<Stats, pa::Error> {
+    let stats = Player::start(settings, sound)?.wait()?;
 
     println!("Done playback");
 
-    Ok(())
+    Ok(stats)
 }
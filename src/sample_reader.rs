@@ -1,48 +1,163 @@
 use crate::types::R;
 use rimd::SMF;
+use std::fs;
+use std::path::Path;
 
-pub fn load_wav(path: &str) -> R<Vec<i16>> {
+// Decoded audio, normalized to [-1, 1].
+pub struct Pcm {
+    // One Vec per channel.
+    pub channels: Vec<Vec<f32>>,
+    pub sample_rate: f64,
+    // The sustain loop, if the file has one.
+    pub sample_loop: Option<SampleLoop>,
+}
+
+// [start, end) in samples.
+#[derive(Copy, Clone, Debug)]
+pub struct SampleLoop {
+    pub start: usize,
+    pub end: usize,
+    // Plays back and forth rather than forward only.
+    pub alternate: bool,
+}
+
+impl Pcm {
+    fn new(num_channels: usize, sample_rate: u32) -> Self {
+        Pcm {
+            channels: vec![vec![]; num_channels],
+            sample_rate: sample_rate as f64,
+            sample_loop: None,
+        }
+    }
+
+    // Appends interleaved samples.
+    fn extend_interleaved(&mut self, ss: impl Iterator<Item=f32>) {
+        let n = self.channels.len();
+        for (i, x) in ss.enumerate() {
+            self.channels[i % n].push(x);
+        }
+    }
+}
+
+pub fn load_wav(path: &str) -> R<Pcm> {
     let reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let mut pcm = Pcm::new(spec.channels as usize, spec.sample_rate);
 
-    let ss: hound::Result<Vec<i16>> = reader.into_samples::<i16>()
-        .collect();
+    match spec.sample_format {
+        hound::SampleFormat::Float => {
+            let ss: hound::Result<Vec<f32>> = reader.into_samples().collect();
+            pcm.extend_interleaved(ss?.into_iter());
+        }
+        hound::SampleFormat::Int => {
+            let mult = pcm_max(spec.bits_per_sample as u32);
+            let ss: hound::Result<Vec<i32>> = reader.into_samples().collect();
+            pcm.extend_interleaved(ss?.into_iter().map(|x| x as f32 / mult));
+        }
+    }
+    // hound skips the chunks it doesn't know about.
+    pcm.sample_loop = wav_sample_loop(&fs::read(path)?);
+    Ok(pcm)
+}
 
-    Ok(ss?)
+// The first loop in the smpl chunk of a WAV file.
+pub fn wav_sample_loop(wav: &[u8]) -> Option<SampleLoop> {
+    let (_, riff) = riff_chunks(wav).into_iter().find(|(id, _)| id == b"RIFF")?;
+    let (_, smpl) = riff_chunks(riff.get(4..)?).into_iter()
+        .find(|(id, _)| id == b"smpl")?;
+    let u32_at = |ix: usize| {
+        smpl.get(ix..ix + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    if u32_at(28)? == 0 {
+        return None;
+    }
+    // The first loop record follows the 36 byte header. Its end is the
+    // last sample played.
+    let (start, end) = (u32_at(36 + 8)? as usize, u32_at(36 + 12)? as usize);
+    Some(SampleLoop { start, end: end + 1, alternate: u32_at(36 + 4)? == 1 })
 }
 
-pub fn load_flac(path: &str) -> R<(Vec<f32>, Vec<f32>)> {
+pub fn load_flac(path: &str) -> R<Pcm> {
     let mut r = claxon::FlacReader::open(path)?;
-    let max_block_len = r.streaminfo().max_block_size as usize * 2;
-    let num_samples = r.streaminfo().samples.unwrap() as usize;
-    let mut ch0 = Vec::with_capacity(num_samples);
-    let mut ch1 = Vec::with_capacity(num_samples);
-    let mut buf = Vec::with_capacity(max_block_len);
+    let info = r.streaminfo();
+    let mut pcm = Pcm::new(info.channels as usize, info.sample_rate);
+    let mult = pcm_max(info.bits_per_sample);
+    if let Some(num_samples) = info.samples {
+        for ch in &mut pcm.channels {
+            ch.reserve(num_samples as usize);
+        }
+    }
+    let mut buf = Vec::with_capacity(info.max_block_size as usize * info.channels as usize);
     let mut blocks = r.blocks();
 
     loop {
         match blocks.read_next_or_eof(buf) {
             Ok(Some(block)) => {
-                ch0.extend(block.channel(0)
-                           .iter()
-                           .cloned()
-                           .map(pcm24_to_f32_norm));
-                ch1.extend(block.channel(1)
-                           .iter()
-                           .cloned()
-                           .map(pcm24_to_f32_norm));
+                for (c, ch) in pcm.channels.iter_mut().enumerate() {
+                    ch.extend(block.channel(c as u32)
+                              .iter()
+                              .map(|&x| x as f32 / mult));
+                }
                 buf = block.into_buffer();
             },
             Ok(None) => break, // End of file.
-            Err(_) => panic!("failed to decode")
+            Err(e) => return Err(e.into()),
         }
     }
 
-    Ok((ch0, ch1))
+    Ok(pcm)
+}
+
+// Picks the decoder by the file extension. Files without a loop of their
+// own take the one in their loop manifest, if any.
+pub fn load_pcm(path: &str) -> R<Pcm> {
+    let ext = Path::new(path).extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    let mut pcm = match ext.as_deref() {
+        Some("wav") => load_wav(path)?,
+        Some("flac") => load_flac(path)?,
+        _ => return Err(format!("Unsupported sample format: {}", path).into()),
+    };
+    if pcm.sample_loop.is_none() {
+        pcm.sample_loop = load_loop_manifest(path);
+    }
+    Ok(pcm)
+}
+
+pub fn loop_manifest_path(sample_path: &str) -> String {
+    format!("{}.loop", sample_path)
+}
+
+// The loop in the sidecar manifest of a sample: a line of "START END",
+// optionally followed by "alternate".
+pub fn load_loop_manifest(sample_path: &str) -> Option<SampleLoop> {
+    let text = fs::read_to_string(loop_manifest_path(sample_path)).ok()?;
+    let words: Vec<&str> = text.split_whitespace().collect();
+    Some(SampleLoop {
+        start: words.first()?.parse().ok()?,
+        end: words.get(1)?.parse().ok()?,
+        alternate: words.get(2) == Some(&"alternate"),
+    })
 }
 
-fn pcm24_to_f32_norm(x: i32) -> f32 {
-    let mult = (i32::max_value() >> 8) as f32;
-    x as f32 / mult
+// Full scale of signed PCM of the given bit depth.
+fn pcm_max(bits: u32) -> f32 {
+    (1i64 << (bits - 1)) as f32
+}
+
+// Splits a sequence of RIFF chunks into (id, body). RIFF and LIST bodies
+// start with their 4 byte form type, followed by more chunks.
+pub fn riff_chunks(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut chunks = vec![];
+    while data.len() >= 8 {
+        let len = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let body = &data[8..(8 + len).min(data.len())];
+        chunks.push((&data[..4], body));
+        // Chunks are padded to even sizes.
+        data = &data[(8 + len + len % 2).min(data.len())..];
+    }
+    chunks
 }
 
 pub fn read_midi(path: &str) -> R<SMF> {
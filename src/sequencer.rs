@@ -0,0 +1,73 @@
+use rimd::{
+    SMF,
+    SMFFormat,
+    Track,
+    TrackEvent,
+    Event,
+    MetaEvent,
+    MetaCommand,
+};
+
+// Flattens all tracks of a MIDI file into a single delta-timed event stream
+// that can be fed into MidiSyn.
+pub fn sequence(f: &SMF) -> Vec<TrackEvent> {
+    match f.format {
+        SMFFormat::Single => {
+            f.tracks.iter().take(1).flat_map(|t| t.events.iter().cloned()).collect()
+        }
+        SMFFormat::MultiTrack => merge_tracks(&f.tracks),
+        SMFFormat::MultiSong => {
+            // Independent sequences: play them one after another.
+            f.tracks.iter().flat_map(|t| t.events.iter().cloned()).collect()
+        }
+    }
+}
+
+// Merges simultaneous tracks by absolute tick. Tempo changes are only taken
+// from the first (conductor) track.
+pub fn merge_tracks(tracks: &[Track]) -> Vec<TrackEvent> {
+    // (absolute tick, track index, event), in track order.
+    let mut evs = vec![];
+    let mut end = 0;
+    for (ix, t) in tracks.iter().enumerate() {
+        let mut tick = 0;
+        for te in &t.events {
+            tick += te.vtime;
+            if is_end_of_track(te) || (ix != 0 && is_tempo(te)) {
+                continue;
+            }
+            evs.push((tick, ix, &te.event));
+        }
+        end = end.max(tick);
+    }
+
+    // Stable, so events at the same tick keep their track and in-track order.
+    evs.sort_by_key(|&(tick, ix, _)| (tick, ix));
+
+    let mut out = Vec::with_capacity(evs.len() + 1);
+    let mut last = 0;
+    for (tick, _, e) in evs {
+        out.push(TrackEvent { vtime: tick - last, event: e.clone() });
+        last = tick;
+    }
+    out.push(end_of_track(end - last));
+    out
+}
+
+fn is_end_of_track(te: &TrackEvent) -> bool {
+    match &te.event {
+        Event::Meta(m) => matches!(m.command, MetaCommand::EndOfTrack),
+        _ => false,
+    }
+}
+
+fn is_tempo(te: &TrackEvent) -> bool {
+    match &te.event {
+        Event::Meta(m) => matches!(m.command, MetaCommand::TempoSetting),
+        _ => false,
+    }
+}
+
+fn end_of_track(vtime: u64) -> TrackEvent {
+    TrackEvent { vtime, event: Event::Meta(MetaEvent::end_of_track()) }
+}
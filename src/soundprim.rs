@@ -14,6 +14,72 @@ pub fn sinewave(freq: f64, ticks: usize, sample_rate: f64) -> impl Sound {
     })
 }
 
+// Linearly interpolated sample at a fractional index. Out of range is silence.
+pub fn sample_at(ss: &[f32], pos: f64) -> f32 {
+    let ix = pos as usize;
+    let frac = (pos - ix as f64) as f32;
+    let x0 = ss.get(ix).cloned().unwrap_or(0.);
+    let x1 = ss.get(ix + 1).cloned().unwrap_or(0.);
+    x0 + (x1 - x0) * frac
+}
+
+// Equal power pan law. pan goes from 0 (left) to 1 (right), giving the
+// (left, right) gains.
+pub fn pan_gains(pan: f32) -> (f32, f32) {
+    let theta = pan.clamp(0., 1.) * PI / 2.;
+    (theta.cos(), theta.sin())
+}
+
+// One pole smoothing of a control value to avoid zipper noise. Yields the
+// smoothed value for each sample.
+pub struct Smoother {
+    value: f32,
+    target: f32,
+    coef: f32,
+}
+
+impl Smoother {
+    // time is the time constant in seconds.
+    pub fn new(value: f32, time: f64, sample_rate: f64) -> Self {
+        Self {
+            value,
+            target: value,
+            coef: (-1. / (time * sample_rate)).exp() as f32,
+        }
+    }
+
+    pub fn set(&mut self, target: f32) {
+        self.target = target;
+    }
+
+    // Jumps to the target, e.g. when nothing is audible anyway.
+    pub fn settle(&mut self) {
+        self.value = self.target;
+    }
+
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+}
+
+impl Iterator for Smoother {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.value = self.target + (self.value - self.target) * self.coef;
+        Some(self.value)
+    }
+}
+
+// Stereo version of sample_at.
+pub fn frame_at(ss: &[Frame], pos: f64) -> Frame {
+    let ix = pos as usize;
+    let frac = (pos - ix as f64) as f32;
+    let [l0, r0] = ss.get(ix).cloned().unwrap_or([0., 0.]);
+    let [l1, r1] = ss.get(ix + 1).cloned().unwrap_or([0., 0.]);
+    [l0 + (l1 - l0) * frac, r0 + (r1 - r0) * frac]
+}
+
 pub struct Envelope {
     pub attack: f64,
     pub attack_plier: f64,
@@ -85,6 +151,147 @@ impl Envelope {
                                   0., release,
                                   self.sample_rate))
     }
+
+    // The level at time t, given as a fraction of the duration.
+    pub fn level(&self, t: f64) -> f64 {
+        let stages = [
+            (self.attack, 0., self.attack_plier),
+            (self.decay, self.attack_plier, self.decay_plier),
+            (self.sustain, self.decay_plier, self.sustain_plier),
+            (self.release, self.sustain_plier, 0.),
+        ];
+        let mut t = t;
+        for (len, y0, y1) in stages {
+            if t < len {
+                return (y0 + t / len * (y1 - y0)) * self.amp;
+            }
+            t -= len;
+        }
+        0.
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Curve {
+    Linear,
+    // Fast at first and then slowing down, like a charging capacitor.
+    Exponential,
+}
+
+// Steepness of Curve::Exponential.
+const EXP_K: f64 = 5.0;
+
+impl Curve {
+    // Goes from 0 to 1 as x does.
+    fn rise(self, x: f64) -> f64 {
+        match self {
+            Curve::Linear => x,
+            Curve::Exponential => (1. - (-EXP_K * x).exp()) / (1. - (-EXP_K).exp()),
+        }
+    }
+}
+
+// Attack, decay, sustain, release envelope driven by a gate: attack and
+// decay run once the gate opens, sustain holds until it closes, and release
+// falls from whatever level it is at then. Times are in seconds, sustain is
+// a level from 0 to 1.
+#[derive(Copy, Clone, Debug)]
+pub struct Adsr {
+    pub attack: f64,
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+    pub curve: Curve,
+}
+
+impl Adsr {
+    // Opens the gate.
+    pub fn make(&self, sample_rate: f64) -> AdsrEnv {
+        AdsrEnv {
+            adsr: *self,
+            dt: 1. / sample_rate,
+            stage: AdsrStage::Attack,
+            t: 0.,
+            level: 0.,
+            release_from: 0.,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum AdsrStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Done,
+}
+
+// A running Adsr. Yields the level for each sample, ending once released
+// and faded out, or once sustaining at 0.
+pub struct AdsrEnv {
+    adsr: Adsr,
+    dt: f64,
+    stage: AdsrStage,
+    // Seconds into the stage.
+    t: f64,
+    level: f64,
+    release_from: f64,
+}
+
+impl AdsrEnv {
+    // Closes the gate.
+    pub fn release(&mut self) {
+        if self.stage != AdsrStage::Release && self.stage != AdsrStage::Done {
+            self.stage = AdsrStage::Release;
+            self.t = 0.;
+            self.release_from = self.level;
+        }
+    }
+
+    pub fn is_released(&self) -> bool {
+        matches!(self.stage, AdsrStage::Release | AdsrStage::Done)
+    }
+
+    fn enter(&mut self, stage: AdsrStage) {
+        self.stage = stage;
+        self.t = 0.;
+    }
+}
+
+impl Iterator for AdsrEnv {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        use self::AdsrStage::*;
+
+        let Adsr { attack, decay, sustain, release, curve } = self.adsr;
+        loop {
+            self.level = match self.stage {
+                Attack if self.t >= attack => {
+                    self.enter(Decay);
+                    continue;
+                }
+                Decay if self.t >= decay => {
+                    self.enter(Sustain);
+                    continue;
+                }
+                Release if self.t >= release => {
+                    self.enter(Done);
+                    continue;
+                }
+                Attack => curve.rise(self.t / attack),
+                Decay => sustain + (1. - sustain) * (1. - curve.rise(self.t / decay)),
+                Sustain if sustain <= 0. => return None,
+                Sustain => sustain,
+                Release => self.release_from * (1. - curve.rise(self.t / release)),
+                Done => return None,
+            };
+            break;
+        }
+        self.t += self.dt;
+        Some(self.level as f32)
+    }
 }
 
 fn interpolate_to(y0: f64, y1: f64, t: f64,
@@ -106,3 +313,55 @@ pub fn superpos(x: impl Sound, y: impl Sound) -> impl Sound {
          }
      })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // At 1 kHz, so that a millisecond is a sample.
+    fn adsr(attack: f64, decay: f64, sustain: f64, release: f64) -> AdsrEnv {
+        Adsr { attack, decay, sustain, release, curve: Curve::Linear }.make(1000.)
+    }
+
+    #[test]
+    fn runs_through_stages() {
+        let mut env = adsr(0.01, 0.01, 0.5, 0.02);
+        let attack: Vec<f32> = env.by_ref().take(10).collect();
+        assert_eq!(attack[0], 0.);
+        assert!(attack.windows(2).all(|w| w[1] > w[0]));
+        let decay: Vec<f32> = env.by_ref().take(10).collect();
+        assert_eq!(decay[0], 1.);
+        assert!(decay.windows(2).all(|w| w[1] < w[0] && w[1] > 0.5));
+        assert!(env.by_ref().take(1000).all(|x| x == 0.5));
+        assert!(!env.is_released());
+
+        env.release();
+        assert!(env.is_released());
+        let release: Vec<f32> = env.collect();
+        assert_eq!(release.len(), 20);
+        assert_eq!(release[0], 0.5);
+        assert!(release.windows(2).all(|w| w[1] < w[0]));
+    }
+
+    #[test]
+    fn releases_from_current_level() {
+        let mut env = adsr(0.1, 0.1, 1., 0.01);
+        let level = env.by_ref().take(50).last().unwrap();
+        env.release();
+        assert_eq!(env.next(), Some(level));
+        assert_eq!(env.count(), 9);
+    }
+
+    #[test]
+    fn ends_without_sustain() {
+        assert_eq!(adsr(0.01, 0.01, 0., 1.).count(), 20);
+    }
+
+    #[test]
+    fn exponential_curve_spans_0_to_1() {
+        let c = Curve::Exponential;
+        assert!(c.rise(0.).abs() < 1e-12 && (c.rise(1.) - 1.).abs() < 1e-12);
+        // Rises faster than linear at first.
+        assert!(c.rise(0.2) > 0.2 && c.rise(0.5) < c.rise(0.6));
+    }
+}
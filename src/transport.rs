@@ -0,0 +1,123 @@
+use crate::midisyn::MidiSyn;
+use std::sync::mpsc::{channel, Sender, Receiver};
+
+pub enum Command {
+    Pause,
+    Resume,
+    // Seconds from the start.
+    SeekTime(f64),
+    // 1-based.
+    SeekBar(u32),
+    // [first bar, last bar], or None to stop looping.
+    LoopBars(Option<(u32, u32)>),
+}
+
+// Streams a MidiSyn as interleaved stereo samples, like MidiSyn::samples,
+// while taking commands from a TransportHandle between blocks.
+pub struct Transport {
+    syn: MidiSyn,
+    commands: Receiver<Command>,
+    paused: bool,
+    buf: Vec<f32>,
+    // Next sample in buf.
+    ix: usize,
+    playing: bool,
+}
+
+// Controls a Transport from another thread.
+#[derive(Clone)]
+pub struct TransportHandle(Sender<Command>);
+
+impl Transport {
+    pub fn new(syn: MidiSyn, block_len: usize) -> (Self, TransportHandle) {
+        let (tx, rx) = channel();
+        let transport = Transport {
+            syn,
+            commands: rx,
+            paused: false,
+            buf: vec![0.0; block_len * 2],
+            ix: block_len * 2,
+            playing: true,
+        };
+        (transport, TransportHandle(tx))
+    }
+
+    fn do_command(&mut self, c: Command) {
+        match c {
+            Command::Pause => self.paused = true,
+            Command::Resume => self.paused = false,
+            Command::SeekTime(secs) => {
+                let tick = self.syn.time_to_tick(secs.max(0.0));
+                self.syn.seek(tick);
+                self.playing = true;
+            }
+            Command::SeekBar(bar) => {
+                let tick = self.syn.bar_to_tick(bar);
+                self.syn.seek(tick);
+                self.playing = true;
+            }
+            Command::LoopBars(bars) => {
+                let region = bars.map(|(first, last)| {
+                    (self.syn.bar_to_tick(first), self.syn.bar_to_tick(last + 1))
+                });
+                self.syn.set_loop(region);
+                self.playing = true;
+            }
+        }
+    }
+}
+
+impl Iterator for Transport {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.ix == self.buf.len() {
+            while let Ok(c) = self.commands.try_recv() {
+                self.do_command(c);
+            }
+            if !self.playing {
+                return None;
+            }
+            if self.paused {
+                // Keep the output going with silence.
+                self.buf.fill(0.0);
+            } else {
+                self.playing = self.syn.render(&mut self.buf);
+            }
+            self.ix = 0;
+        }
+        self.ix += 1;
+        Some(self.buf[self.ix - 1])
+    }
+}
+
+impl TransportHandle {
+    // Errors are ignored: they only mean that the playback has finished.
+    fn send(&self, c: Command) {
+        let _ = self.0.send(c);
+    }
+
+    pub fn pause(&self) {
+        self.send(Command::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(Command::Resume);
+    }
+
+    pub fn seek_time(&self, secs: f64) {
+        self.send(Command::SeekTime(secs));
+    }
+
+    pub fn seek_bar(&self, bar: u32) {
+        self.send(Command::SeekBar(bar));
+    }
+
+    pub fn loop_bars(&self, first: u32, last: u32) {
+        self.send(Command::LoopBars(Some((first, last))));
+    }
+
+    pub fn stop_looping(&self) {
+        self.send(Command::LoopBars(None));
+    }
+}
@@ -1,8 +1,15 @@
 use std::error::Error;
 
-pub trait Sound = SoundRef + 'static;
-pub trait SoundRef = Iterator<Item=f32>;
+// A stream of samples.
+pub trait SoundRef: Iterator<Item=f32> {}
+impl<T: Iterator<Item=f32>> SoundRef for T {}
+
+pub trait Sound: SoundRef + Send + 'static {}
+impl<T: SoundRef + Send + 'static> Sound for T {}
 
 pub type R<A> = Result<A, Box<dyn Error>>;
 
+// A (left, right) pair of samples.
+pub type Frame = [f32; 2];
+
 pub const SAMPLE_RATE: f64 = 44_100.0;
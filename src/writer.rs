@@ -1,4 +1,6 @@
 use crate::types::{R, SoundRef};
+use crate::sample_reader::{riff_chunks, loop_manifest_path, SampleLoop};
+use std::fs;
 
 pub fn save_wav(s: impl SoundRef, name: &str, channels: u16) -> R<()> {
     let spec = hound::WavSpec {
@@ -19,3 +21,59 @@ pub fn save_wav(s: impl SoundRef, name: &str, channels: u16) -> R<()> {
     }
     Ok(())
 }
+
+pub fn save_loop_manifest(sample_path: &str, l: &SampleLoop) -> R<()> {
+    let alternate = if l.alternate { " alternate" } else { "" };
+    fs::write(loop_manifest_path(sample_path),
+              format!("{} {}{}\n", l.start, l.end, alternate))?;
+    Ok(())
+}
+
+// Rewrites a WAV file with l as its only smpl loop.
+pub fn write_smpl(path: &str, l: &SampleLoop) -> R<()> {
+    if l.end <= l.start {
+        return Err(format!("loop {}..{} is empty", l.start, l.end).into());
+    }
+    let data = fs::read(path)?;
+    let wave = match riff_chunks(&data).first() {
+        Some((b"RIFF", body)) if body.starts_with(b"WAVE") => &body[4..],
+        _ => return Err(format!("{} is not a WAV file", path).into()),
+    };
+
+    let mut body = b"WAVE".to_vec();
+    let mut sample_rate = 44100;
+    for (id, chunk) in riff_chunks(wave) {
+        if id == b"fmt " && chunk.len() >= 8 {
+            sample_rate = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        }
+        if id != b"smpl" {
+            push_chunk(&mut body, id, chunk);
+        }
+    }
+
+    let mut smpl = vec![];
+    // Manufacturer, product, sample period in ns, MIDI unity note, pitch
+    // fraction, SMPTE format and offset, number of loops, sampler data.
+    let header = [0, 0, 1_000_000_000 / sample_rate.max(1), 60, 0, 0, 0, 1, 0];
+    // Cue point, type (0 forward, 1 alternate), start, end (inclusive),
+    // fraction, play count (0 forever).
+    let lp = [0, l.alternate as u32, l.start as u32, l.end as u32 - 1, 0, 0];
+    for x in header.iter().chain(&lp) {
+        smpl.extend_from_slice(&x.to_le_bytes());
+    }
+    push_chunk(&mut body, b"smpl", &smpl);
+
+    let mut out = vec![];
+    push_chunk(&mut out, b"RIFF", &body);
+    fs::write(path, out)?;
+    Ok(())
+}
+
+fn push_chunk(out: &mut Vec<u8>, id: &[u8], body: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+}
@@ -0,0 +1,71 @@
+use music_syn::{
+    sample_reader::read_midi,
+    midisyn::MidiSyn,
+    instr::Piano,
+    sequencer::sequence,
+};
+use rimd::{SMF, SMFFormat, Track, TrackEvent, Event};
+
+// Only render the beginning of the piece to keep the test reasonably fast.
+const NUM_EVENTS: usize = 400;
+
+fn track(events: Vec<TrackEvent>) -> Track {
+    Track { copyright: None, name: None, events }
+}
+
+fn head_of(f: &SMF) -> SMF {
+    let events = f.tracks[0].events.iter().take(NUM_EVENTS).cloned().collect();
+    SMF { format: SMFFormat::Single, tracks: vec![track(events)], division: f.division }
+}
+
+// Splits a format 0 file into format 1: a conductor track with all the meta
+// events, followed by one track per used MIDI channel.
+fn to_format1(f: &SMF) -> SMF {
+    let mut abs: Vec<Vec<(u64, &Event)>> = vec![vec![]; 17];
+    let mut tick = 0;
+    for te in &f.tracks[0].events {
+        tick += te.vtime;
+        let ix = match &te.event {
+            Event::Midi(m) => 1 + m.channel().unwrap_or(0) as usize,
+            Event::Meta(_) => 0,
+        };
+        abs[ix].push((tick, &te.event));
+    }
+
+    let tracks = abs.into_iter()
+        .enumerate()
+        .filter(|(ix, evs)| *ix == 0 || !evs.is_empty())
+        .map(|(_, evs)| {
+            let mut last = 0;
+            track(evs.into_iter().map(|(tick, e)| {
+                let vtime = tick - last;
+                last = tick;
+                TrackEvent { vtime, event: e.clone() }
+            }).collect())
+        })
+        .collect();
+    SMF { format: SMFFormat::MultiTrack, tracks, division: f.division }
+}
+
+fn render(f: &SMF, piano: Piano) -> Vec<f32> {
+    let mut m = MidiSyn::new(piano);
+    m.track_state.div = f.division as usize;
+    m.syn(&sequence(f)).to_vec()
+}
+
+#[test]
+fn format1_renders_like_format0() {
+    let f0 = head_of(&read_midi("midi/mz_545_1_format0.mid").unwrap());
+    let f1 = to_format1(&f0);
+    assert!(f1.tracks.len() > 1);
+
+    let piano = Piano::load("samples/normed").unwrap();
+    let out0 = render(&f0, piano.clone());
+    let out1 = render(&f1, piano);
+
+    assert_eq!(out0.len(), out1.len());
+    // Voices are mixed in no particular order, so allow for rounding.
+    for (x, y) in out0.iter().zip(&out1) {
+        assert!((x - y).abs() < 1e-4);
+    }
+}